@@ -0,0 +1,143 @@
+/*
+    Who is allowed to wake the real server. Either a copy of the real server's whitelist.json, or a
+    plain text file with one name or UUID per line (and # comments).
+    Nothing a client sends before encryption is verified, so this keeps out scanners and strangers,
+    not someone who knows a member's name.
+*/
+
+use crate::config::ServerConfig;
+use crate::error::Error;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+#[derive(Debug, Default)]
+pub struct Allowlist {
+    // Lowercase, since Minecraft names aren't case sensitive
+    names: HashSet<String>,
+    uuids: HashSet<u128>,
+}
+
+// An entry in the vanilla server's whitelist.json
+#[derive(Deserialize)]
+struct WhitelistEntry {
+    uuid: Option<String>,
+    name: Option<String>,
+}
+
+impl Allowlist {
+    /// The allowlist from server.allowlist, if there is one
+    pub fn from_config(config: &ServerConfig) -> Result<Option<Self>, Error> {
+        config.allowlist.as_deref().map(Self::load).transpose()
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path).map_err(|e| {
+            Error::config(format!("Couldn't read allowlist {}: {}", path.display(), e))
+        })?;
+        Self::parse(&contents)
+            .map_err(|e| Error::config(format!("Bad allowlist {}: {}", path.display(), e)))
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, Error> {
+        let mut allowlist = Allowlist::default();
+        if contents.trim_start().starts_with('[') {
+            let entries: Vec<WhitelistEntry> = serde_json::from_str(contents)?;
+            for entry in entries {
+                if let Some(uuid) = entry.uuid {
+                    allowlist.uuids.insert(parse_uuid(&uuid)?);
+                }
+                if let Some(name) = entry.name {
+                    allowlist.names.insert(name.to_lowercase());
+                }
+            }
+            return Ok(allowlist);
+        }
+        for line in contents.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            // Names are at most 16 characters, so anything that parses as a UUID is one
+            match parse_uuid(line) {
+                Ok(uuid) => allowlist.uuids.insert(uuid),
+                Err(_) => allowlist.names.insert(line.to_lowercase()),
+            };
+        }
+        Ok(allowlist)
+    }
+
+    /// Whether a player may wake the real server. Either their name or UUID has to be listed.
+    pub fn allows(&self, name: &str, uuid: Option<u128>) -> bool {
+        uuid.is_some_and(|uuid| self.uuids.contains(&uuid))
+            || self.names.contains(&name.to_lowercase())
+    }
+}
+
+/// A UUID with or without the dashes
+pub fn parse_uuid(uuid: &str) -> Result<u128, Error> {
+    let hex: String = uuid.chars().filter(|c| *c != '-').collect();
+    if hex.len() != 32 {
+        return Err(Error::config(format!("\"{}\" isn't a UUID", uuid)));
+    }
+    u128::from_str_radix(&hex, 16).map_err(|_| Error::config(format!("\"{}\" isn't a UUID", uuid)))
+}
+
+/// The usual dashed form of a UUID
+pub fn format_uuid(uuid: u128) -> String {
+    let hex = format!("{:032x}", uuid);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOTCH: u128 = 0x069a79f4_44e9_4726_a5be_fca90e38aaf5;
+
+    #[test]
+    fn test_whitelist_json() -> Result<(), Error> {
+        let allowlist = Allowlist::parse(
+            r#"[
+                {"uuid": "069a79f4-44e9-4726-a5be-fca90e38aaf5", "name": "Notch"},
+                {"uuid": "853c80ef-3c37-49fd-aa49-938b674adae6", "name": "jeb_"}
+            ]"#,
+        )?;
+        assert!(allowlist.allows("notch", None));
+        // Renamed, but still the same account
+        assert!(allowlist.allows("Notch2", Some(NOTCH)));
+        assert!(!allowlist.allows("Dinnerbone", None));
+        assert!(!allowlist.allows("Dinnerbone", Some(1)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_plain_list() -> Result<(), Error> {
+        let allowlist = Allowlist::parse(
+            "# Regulars\nSteve\n  Alex  # comes on weekends\n\n069a79f444e94726a5befca90e38aaf5\n",
+        )?;
+        assert!(allowlist.allows("steve", None));
+        assert!(allowlist.allows("ALEX", Some(5)));
+        assert!(allowlist.allows("someone", Some(NOTCH)));
+        assert!(!allowlist.allows("Herobrine", None));
+        Ok(())
+    }
+
+    #[test]
+    fn test_uuids() -> Result<(), Error> {
+        assert_eq!(NOTCH, parse_uuid("069a79f4-44e9-4726-a5be-fca90e38aaf5")?);
+        assert_eq!("069a79f4-44e9-4726-a5be-fca90e38aaf5", format_uuid(NOTCH));
+        assert!(parse_uuid("Notch").is_err());
+        assert!(Allowlist::parse(r#"[{"uuid": "nope"}]"#).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,261 @@
+use crate::error::Error;
+use log::LevelFilter;
+use std::env;
+
+pub const USAGE: &str = "\
+Usage: facade [-c <config.toml>] [-v | -vv | -q] <command> [options]
+
+Commands:
+    run                 Run the whole lifecycle: fake server, wake the real server on login,
+                        proxy to it until it empties, then shut it down again
+        --bind <addr>       Address to listen on (default server.listen)
+    serve               Run the fake server until someone tries to log in
+        --bind <addr>       Address to listen on (default server.listen)
+    proxy               Proxy every connection to a real server
+        --bind <addr>       Address to listen on (default server.listen)
+        --backend <addr>    Address of the real server (default backend.address)
+    rcon exec <command> Run a command on a real server over rcon
+        --addr <addr>       Rcon address of the real server (default rcon.address)
+        --password <pw>     Rcon password (or FACADE_RCON_PASSWORD, or rcon.password)
+    ping                Ping a server the way the Minecraft server list does
+        --addr <addr>       Address of the server (default 127.0.0.1:25565)
+    help                Print this message
+
+Global options:
+    -c, --config <path> Read settings from a toml config file; flags override it
+    -v, -vv             Log more (debug, trace)
+    -q                  Only log errors
+    RUST_LOG, if set, overrides the log level entirely
+";
+
+pub const DEFAULT_PING_ADDR: &str = "127.0.0.1:25565";
+pub const PASSWORD_ENV: &str = "FACADE_RCON_PASSWORD";
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct Args {
+    pub log_level: LevelFilter,
+    pub config_path: Option<String>,
+    pub command: Command,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum Command {
+    // Anything left as None comes from the config file
+    Run {
+        bind: Option<String>,
+    },
+    Serve {
+        bind: Option<String>,
+    },
+    Proxy {
+        bind: Option<String>,
+        backend: Option<String>,
+    },
+    RconExec {
+        addr: Option<String>,
+        password: Option<String>,
+        command: String,
+    },
+    Ping {
+        addr: String,
+    },
+    Help,
+}
+
+/// Parse the command line, not including the program name
+pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Args, Error> {
+    let mut log_level = LevelFilter::Info;
+    let mut config_path = None;
+    let mut rest = vec![];
+    // Global flags can go anywhere before a `--`, so pull them out before looking at the command.
+    // Everything after one is left alone, like an rcon command with its own -v.
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--" => {
+                rest.push(arg);
+                rest.extend(args.by_ref());
+            }
+            "-v" => log_level = LevelFilter::Debug,
+            "-vv" => log_level = LevelFilter::Trace,
+            "-q" => log_level = LevelFilter::Error,
+            "-c" | "--config" => {
+                config_path = Some(
+                    args.next()
+                        .ok_or_else(|| Error::config("--config needs a value"))?,
+                );
+            }
+            _ => rest.push(arg),
+        }
+    }
+    let mut rest = rest.into_iter();
+    let command = match rest.next().as_deref() {
+        Some("run") => {
+            let opts = Options::parse(rest, &["bind"])?;
+            Command::Run {
+                bind: opts.get("bind"),
+            }
+        }
+        Some("serve") => {
+            let opts = Options::parse(rest, &["bind"])?;
+            Command::Serve {
+                bind: opts.get("bind"),
+            }
+        }
+        Some("proxy") => {
+            let opts = Options::parse(rest, &["bind", "backend"])?;
+            Command::Proxy {
+                bind: opts.get("bind"),
+                backend: opts.get("backend"),
+            }
+        }
+        Some("rcon") => match rest.next().as_deref() {
+            Some("exec") => {
+                let opts = Options::parse(rest, &["addr", "password"])?;
+                let password = opts.get("password").or_else(|| env::var(PASSWORD_ENV).ok());
+                if opts.positional.is_empty() {
+                    return Err(Error::config("rcon exec needs a command to run"));
+                }
+                Command::RconExec {
+                    addr: opts.get("addr"),
+                    password,
+                    command: opts.positional.join(" "),
+                }
+            }
+            Some(other) => return Err(Error::config(format!("Unknown rcon command {}", other))),
+            None => return Err(Error::config("rcon needs a subcommand")),
+        },
+        Some("ping") => {
+            let opts = Options::parse(rest, &["addr"])?;
+            Command::Ping {
+                addr: opts.get_or("addr", DEFAULT_PING_ADDR),
+            }
+        }
+        Some("help") | Some("-h") | Some("--help") | None => Command::Help,
+        Some(other) => return Err(Error::config(format!("Unknown command {}", other))),
+    };
+    Ok(Args {
+        log_level,
+        config_path,
+        command,
+    })
+}
+
+// `--name value` / `--name=value` options, plus whatever is left over
+struct Options {
+    named: Vec<(String, String)>,
+    positional: Vec<String>,
+}
+
+impl Options {
+    fn parse(args: impl Iterator<Item = String>, allowed: &[&str]) -> Result<Self, Error> {
+        let mut named = vec![];
+        let mut positional = vec![];
+        let mut args = args.peekable();
+        while let Some(arg) = args.next() {
+            if arg == "--" {
+                // Everything after a bare -- is positional, so rcon commands can start with -
+                positional.extend(args.by_ref());
+                break;
+            }
+            let flag = match arg.strip_prefix("--") {
+                Some(flag) => flag,
+                None => {
+                    positional.push(arg);
+                    continue;
+                }
+            };
+            let (name, value) = match flag.find('=') {
+                Some(idx) => (flag[..idx].to_owned(), flag[idx + 1..].to_owned()),
+                None => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| Error::config(format!("--{} needs a value", flag)))?;
+                    (flag.to_owned(), value)
+                }
+            };
+            if !allowed.contains(&name.as_str()) {
+                return Err(Error::config(format!("Unknown option --{}", name)));
+            }
+            named.push((name, value));
+        }
+        Ok(Options { named, positional })
+    }
+
+    fn get(&self, name: &str) -> Option<String> {
+        // Later flags win, like most command line tools
+        self.named
+            .iter()
+            .rev()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.clone())
+    }
+
+    fn get_or(&self, name: &str, default: &str) -> String {
+        self.get(name).unwrap_or_else(|| default.to_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_str(args: &str) -> Result<Args, Error> {
+        parse(args.split_whitespace().map(|s| s.to_owned()))
+    }
+
+    #[test]
+    fn test_serve_defaults() -> Result<(), Error> {
+        let args = parse_str("serve")?;
+        assert_eq!(LevelFilter::Info, args.log_level);
+        assert_eq!(None, args.config_path);
+        assert_eq!(Command::Serve { bind: None }, args.command);
+        Ok(())
+    }
+
+    #[test]
+    fn test_proxy_flags() -> Result<(), Error> {
+        let args =
+            parse_str("-v proxy --bind=127.0.0.1:1 --backend 10.0.0.2:25565 -c facade.toml")?;
+        assert_eq!(LevelFilter::Debug, args.log_level);
+        assert_eq!(Some("facade.toml".to_owned()), args.config_path);
+        assert_eq!(
+            Command::Proxy {
+                bind: Some("127.0.0.1:1".to_owned()),
+                backend: Some("10.0.0.2:25565".to_owned()),
+            },
+            args.command
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_rcon_exec() -> Result<(), Error> {
+        let args = parse_str("rcon exec --password hunter2 say hi there -vv")?;
+        assert_eq!(LevelFilter::Trace, args.log_level);
+        assert_eq!(
+            Command::RconExec {
+                addr: None,
+                password: Some("hunter2".to_owned()),
+                command: "say hi there".to_owned(),
+            },
+            args.command
+        );
+        assert!(parse_str("rcon exec --password hunter2").is_err()); // no command
+        let args = parse_str("-q rcon exec -- say -v -c")?;
+        assert_eq!(LevelFilter::Error, args.log_level);
+        assert_eq!(None, args.config_path);
+        match args.command {
+            Command::RconExec { command, .. } => assert_eq!("say -v -c", command),
+            other => panic!("expected rcon exec, got {:?}", other),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_unknown() {
+        assert!(parse_str("serve --backend x").is_err());
+        assert!(parse_str("explode").is_err());
+        assert_eq!(Command::Help, parse_str("").unwrap().command);
+    }
+}
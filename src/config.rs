@@ -0,0 +1,608 @@
+use crate::error::Error;
+use crate::proxy_protocol;
+use crate::server::version;
+use serde::Deserialize;
+use std::{fs, net::IpAddr, path::Path, time::Duration};
+
+/*
+ * Everything the facade can be told about from a toml file. Every field has a default so an empty
+ * file (or no file at all) gives the same behaviour as the old hardcoded values.
+ */
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub backend: BackendConfig,
+    pub rcon: RconConfig,
+    pub idle: IdleConfig,
+    pub provisioner: ProvisionerConfig,
+    pub proxy: ProxyConfig,
+    pub wake: WakeConfig,
+    pub limits: LimitsConfig,
+    pub proxy_protocol: ProxyProtocolConfig,
+}
+
+/// The fake server players see while the real one is asleep
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub listen: String,
+    /// First line of the server list description. Like all the text here, it can use § color codes.
+    pub motd: String,
+    /// Second line of the description, depending on what the real server is doing.
+    /// `{eta}` in starting_status is replaced with something like "about 40 seconds".
+    pub sleeping_status: String,
+    pub starting_status: String,
+    pub online_status: String,
+    pub stopping_status: String,
+    /// Version shown in the server list. Defaults to the real server's release if we know it, or
+    /// to whatever the client is running so it doesn't look incompatible.
+    pub version_name: Option<String>,
+    pub max_players: u32,
+    pub kick_message: String,
+    /// Keep players who log in while the real server boots connected, instead of kicking them
+    pub hold_logins: bool,
+    /// How long to hold them for. The vanilla client gives up after 30 seconds without a packet.
+    pub hold_timeout_secs: u64,
+    /// Sent to held players if the real server isn't up in time. `{eta}` is replaced with
+    /// something like "about 40 seconds".
+    pub hold_expired_message: String,
+    /// Sent to clients that can't play on the real server's version, without waking it.
+    /// `{version}` is replaced with the real server's release, like "1.20.4".
+    pub incompatible_message: String,
+    /// Once the real server has been up, show its status (version, favicon, mod info...) in the
+    /// server list while it's asleep, with our description instead of its own
+    pub passthrough_status: bool,
+    /// Where to save the real server's status so it survives the facade restarting
+    pub status_cache: Option<String>,
+    /// A 64x64 png to show in the server list
+    pub favicon: Option<String>,
+    /// Shown instead of favicon while the real server is asleep
+    pub sleeping_favicon: Option<String>,
+    /// Make players finish the login encryption handshake, and pass the session server's check,
+    /// before they can wake the real server. Encrypted logins can't be handed over, so they're
+    /// always kicked.
+    pub online_mode: bool,
+    /// Only players in this file can wake the real server. Either a copy of its whitelist.json or
+    /// one name or UUID per line.
+    pub allowlist: Option<String>,
+    /// Sent to players who aren't on the allowlist
+    pub not_allowed_message: String,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            listen: "0.0.0.0:25565".to_owned(),
+            motd: "Fake!".to_owned(),
+            sleeping_status: "§7Sleeping - join to wake".to_owned(),
+            starting_status: "§eStarting, ready in {eta}".to_owned(),
+            online_status: "§aOnline".to_owned(),
+            stopping_status: "§cShutting down".to_owned(),
+            version_name: None,
+            max_players: 1,
+            kick_message: "Starting the real server, this could take a bit".to_owned(),
+            hold_logins: false,
+            hold_timeout_secs: 25,
+            hold_expired_message: "The server is still starting, try again in {eta}".to_owned(),
+            incompatible_message: "This server runs {version}".to_owned(),
+            passthrough_status: true,
+            status_cache: None,
+            favicon: None,
+            sleeping_favicon: None,
+            online_mode: false,
+            allowlist: None,
+            not_allowed_message: "You're not on the list for this server".to_owned(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackendConfig {
+    /// Where the real server listens for players
+    pub address: Option<String>,
+    /// How long the real server gets to start answering pings before we give up on it
+    pub start_timeout_secs: u64,
+    /// Roughly how long the real server takes to start, until we've seen it start ourselves
+    pub expected_start_secs: u64,
+    /// The real server's Minecraft release, like "1.20.4", so clients that can't play on it get
+    /// told so instead of waking it. Learned from its status once it's been up, if unset.
+    pub version: Option<String>,
+}
+
+impl Default for BackendConfig {
+    fn default() -> Self {
+        BackendConfig {
+            address: None,
+            start_timeout_secs: 300,
+            expected_start_secs: 60,
+            version: None,
+        }
+    }
+}
+
+impl BackendConfig {
+    pub fn start_timeout(&self) -> Duration {
+        Duration::from_secs(self.start_timeout_secs)
+    }
+
+    pub fn expected_start(&self) -> Duration {
+        Duration::from_secs(self.expected_start_secs)
+    }
+
+    /// The protocol for `version`, which validation has already checked is in the table
+    pub fn protocol(&self) -> Option<i32> {
+        self.version.as_deref().and_then(version::protocol)
+    }
+}
+
+impl ServerConfig {
+    pub fn hold_timeout(&self) -> Duration {
+        Duration::from_secs(self.hold_timeout_secs)
+    }
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RconConfig {
+    pub address: String,
+    pub password: Option<String>,
+}
+
+impl Default for RconConfig {
+    fn default() -> Self {
+        RconConfig {
+            address: "127.0.0.1:25575".to_owned(),
+            password: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct IdleConfig {
+    /// How often to ask the real server who is online
+    pub poll_interval_secs: u64,
+    /// How long the real server has to be empty before it gets saved and shut down
+    pub shutdown_after_secs: u64,
+}
+
+impl Default for IdleConfig {
+    fn default() -> Self {
+        IdleConfig {
+            poll_interval_secs: 30,
+            shutdown_after_secs: 300,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxyConfig {
+    pub mode: ProxyMode,
+    /// How the real server finds out who players are and where they're connecting from
+    pub forwarding: Forwarding,
+    /// The secret Velocity forwarding is signed with, the same as the real server's
+    pub forwarding_secret: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ProxyMode {
+    /// Read the handshake before proxying, so we know who is actually logging in, then replay it
+    #[default]
+    Transparent,
+    /// Copy bytes without looking at them. Every connection counts as a player, so only rcon
+    /// can say when it's empty.
+    Raw,
+}
+
+/// See forwarding.rs
+#[derive(Debug, Default, Deserialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Forwarding {
+    /// The real server sees every player coming from the facade
+    #[default]
+    None,
+    /// Legacy forwarding in the handshake, for `bungeecord: true` in spigot.yml
+    BungeeCord,
+    /// Modern forwarding, signed with forwarding_secret, for Paper's Velocity support
+    Velocity,
+}
+
+/// For running behind a load balancer, which would otherwise be where every connection comes from
+#[derive(Debug, Default, Clone, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxyProtocolConfig {
+    /// Expect a PROXY protocol header (v1 or v2) at the start of every connection. Connections
+    /// without one are closed, since anyone could send one if it were optional.
+    pub accept: bool,
+    /// The load balancers whose headers are believed. Connections from anywhere else are closed,
+    /// or anyone could say they're from wherever they like.
+    pub trusted: Vec<IpAddr>,
+    /// Send a header to the real server, so it sees where players really are. It has to be
+    /// expecting one.
+    pub send: Option<proxy_protocol::Version>,
+}
+
+impl ProxyProtocolConfig {
+    /// Whether a connection from `ip` gets to say where it's really from
+    pub fn trusts(&self, ip: IpAddr) -> bool {
+        self.trusted.contains(&ip)
+    }
+}
+
+// Enough for a handshake with the longest server address a client will send
+const MIN_FRAME_LENGTH: usize = 1024;
+
+/// What the fake server puts up with before closing a connection. It runs on whatever is left on
+/// while the real server sleeps, so it can't afford to be generous.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Connections being handled at once. Any more are closed straight away.
+    pub max_connections: usize,
+    /// New connections from one address
+    pub connections_per_minute: u32,
+    /// Server list pings from one address
+    pub pings_per_minute: u32,
+    /// How long a client gets to send its handshake, and login start if it's logging in
+    pub handshake_timeout_secs: u64,
+    /// How long to wait for each packet after that
+    pub read_timeout_secs: u64,
+    /// The longest packet a client can send us, in bytes. At least 1024.
+    pub max_frame_length: usize,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            max_connections: 128,
+            connections_per_minute: 30,
+            pings_per_minute: 20,
+            handshake_timeout_secs: 5,
+            read_timeout_secs: 10,
+            // Plenty for a login start with a 1.19 signing key
+            max_frame_length: 16 * 1024,
+        }
+    }
+}
+
+impl LimitsConfig {
+    pub fn handshake_timeout(&self) -> Duration {
+        Duration::from_secs(self.handshake_timeout_secs)
+    }
+
+    pub fn read_timeout(&self) -> Duration {
+        Duration::from_secs(self.read_timeout_secs)
+    }
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct WakeConfig {
+    /// Who gets to wake the real server. Defaults to allowlisted if there's a server.allowlist,
+    /// otherwise anyone.
+    pub policy: Option<WakePolicyConfig>,
+    /// Sent to players whose login was noted, but didn't wake the real server
+    pub deferred_message: String,
+}
+
+impl Default for WakeConfig {
+    fn default() -> Self {
+        WakeConfig {
+            policy: None,
+            deferred_message: "Noted! The server starts once enough people want to play".to_owned(),
+        }
+    }
+}
+
+/// See wake.rs for what each of these does
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum WakePolicyConfig {
+    AnyLogin,
+    /// Only players on server.allowlist
+    Allowlisted,
+    DistinctPlayers {
+        count: usize,
+        within_secs: u64,
+    },
+    /// The same player logging in twice
+    Confirm {
+        within_secs: u64,
+    },
+    /// Per address
+    Cooldown {
+        secs: u64,
+    },
+    All {
+        of: Vec<WakePolicyConfig>,
+    },
+    Any {
+        of: Vec<WakePolicyConfig>,
+    },
+}
+
+/// How the real server gets started and stopped
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum ProvisionerConfig {
+    /// Something else starts the server, we only stop it over rcon
+    External {
+        /// How long it gets to go down after `stop`
+        #[serde(default = "default_stop_timeout_secs")]
+        stop_timeout_secs: u64,
+    },
+    /// Run the server as a child process of the facade
+    Local {
+        #[serde(default = "default_local_command")]
+        command: String,
+        #[serde(default = "default_local_args")]
+        args: Vec<String>,
+        working_dir: Option<String>,
+        #[serde(default = "default_stop_timeout_secs")]
+        stop_timeout_secs: u64,
+    },
+    /// Run user-supplied shell commands, e.g. to boot a cloud VM
+    Shell {
+        start: String,
+        stop: String,
+        /// Exits 0 if the server is running
+        status: Option<String>,
+        /// Prints the server's address, for when it isn't known ahead of time
+        address: Option<String>,
+    },
+}
+
+impl Default for ProvisionerConfig {
+    fn default() -> Self {
+        ProvisionerConfig::External {
+            stop_timeout_secs: default_stop_timeout_secs(),
+        }
+    }
+}
+
+fn default_local_command() -> String {
+    "java".to_owned()
+}
+
+fn default_local_args() -> Vec<String> {
+    vec!["-jar".to_owned(), "server.jar".to_owned()]
+}
+
+fn default_stop_timeout_secs() -> u64 {
+    60
+}
+
+impl IdleConfig {
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_secs(self.poll_interval_secs)
+    }
+
+    pub fn shutdown_after(&self) -> Duration {
+        Duration::from_secs(self.shutdown_after_secs)
+    }
+}
+
+impl Config {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path).map_err(|e| {
+            Error::config(format!(
+                "Couldn't read config file {}: {}",
+                path.display(),
+                e
+            ))
+        })?;
+        Self::parse(&contents).map_err(|e| Error::config(format!("{}: {}", path.display(), e)))
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, Error> {
+        let config: Config = toml::from_str(contents)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    // Catch the mistakes toml can't, so they show up at startup rather than on the first login
+    fn validate(&self) -> Result<(), Error> {
+        check_address("server.listen", &self.server.listen)?;
+        if let Some(address) = &self.backend.address {
+            check_address("backend.address", address)?;
+        }
+        if let Some(release) = &self.backend.version {
+            if version::protocol(release).is_none() {
+                return Err(invalid(
+                    "backend.version",
+                    &format!("unknown Minecraft release \"{}\"", release),
+                ));
+            }
+        }
+        if self.server.online_mode && self.server.hold_logins {
+            return Err(invalid(
+                "server.hold_logins",
+                "logins can't be held in online mode, encrypted connections can't be handed over",
+            ));
+        }
+        check_address("rcon.address", &self.rcon.address)?;
+        if let Some(password) = &self.rcon.password {
+            if !password.is_ascii() {
+                return Err(invalid(
+                    "rcon.password",
+                    "rcon only supports ascii passwords",
+                ));
+            }
+        }
+        if self.proxy.forwarding != Forwarding::None && self.proxy.mode == ProxyMode::Raw {
+            return Err(invalid(
+                "proxy.forwarding",
+                "raw proxying never looks at who is logging in, so it can't forward them",
+            ));
+        }
+        if self.proxy.mode == ProxyMode::Raw && self.rcon.password.is_none() {
+            return Err(invalid(
+                "proxy.mode",
+                "raw proxying counts server list pings as players, so it needs rcon.password to \
+                 tell when the real server is empty",
+            ));
+        }
+        let secret = self.proxy.forwarding_secret.as_deref().unwrap_or_default();
+        if self.proxy.forwarding == Forwarding::Velocity && secret.is_empty() {
+            return Err(invalid(
+                "proxy.forwarding_secret",
+                "velocity forwarding needs the secret the real server has",
+            ));
+        }
+        if self.proxy_protocol.accept && self.proxy_protocol.trusted.is_empty() {
+            return Err(invalid(
+                "proxy_protocol.trusted",
+                "needs the addresses of the load balancers sending PROXY headers",
+            ));
+        }
+        if self.idle.poll_interval_secs == 0 {
+            return Err(invalid("idle.poll_interval_secs", "must be at least 1"));
+        }
+        let limits = &self.limits;
+        for (field, value) in [
+            ("limits.max_connections", limits.max_connections as u64),
+            (
+                "limits.connections_per_minute",
+                limits.connections_per_minute.into(),
+            ),
+            ("limits.pings_per_minute", limits.pings_per_minute.into()),
+            (
+                "limits.handshake_timeout_secs",
+                limits.handshake_timeout_secs,
+            ),
+            ("limits.read_timeout_secs", limits.read_timeout_secs),
+        ] {
+            if value == 0 {
+                return Err(invalid(field, "must be at least 1"));
+            }
+        }
+        if limits.max_frame_length < MIN_FRAME_LENGTH {
+            return Err(invalid(
+                "limits.max_frame_length",
+                &format!("must be at least {}", MIN_FRAME_LENGTH),
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn invalid(field: &str, reason: &str) -> Error {
+    Error::config(format!("invalid config field `{}`: {}", field, reason))
+}
+
+// Addresses can be hostnames, so the best we can do without a lookup is check for host:port
+fn check_address(field: &str, address: &str) -> Result<(), Error> {
+    match address.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => Ok(()),
+        _ => Err(invalid(
+            field,
+            &format!("expected host:port, got \"{}\"", address),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_empty_config_is_default() -> Result<(), Error> {
+        assert_eq!(Config::default(), Config::parse("")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_config() -> Result<(), Error> {
+        let config = Config::parse(
+            r#"
+            [server]
+            listen = "0.0.0.0:25566"
+            motd = "Asleep"
+            max_players = 20
+
+            [backend]
+            address = "10.0.0.2:25565"
+
+            [rcon]
+            address = "10.0.0.2:25575"
+            password = "hunter2"
+
+            [idle]
+            shutdown_after_secs = 600
+            "#,
+        )?;
+        assert_eq!("Asleep", config.server.motd);
+        assert_eq!(20, config.server.max_players);
+        assert_eq!(25, config.server.hold_timeout_secs); // unset fields keep their defaults
+        assert_eq!(Some("10.0.0.2:25565".to_owned()), config.backend.address);
+        assert_eq!(Some("hunter2".to_owned()), config.rcon.password);
+        assert_eq!(600, config.idle.shutdown_after_secs);
+        Ok(())
+    }
+
+    #[test]
+    fn test_provisioner_config() -> Result<(), Error> {
+        let config = Config::parse("[provisioner]\nkind = \"local\"\nworking_dir = \"/srv/mc\"")?;
+        assert_eq!(
+            ProvisionerConfig::Local {
+                command: "java".to_owned(),
+                args: vec!["-jar".to_owned(), "server.jar".to_owned()],
+                working_dir: Some("/srv/mc".to_owned()),
+                stop_timeout_secs: 60,
+            },
+            config.provisioner
+        );
+        let config = Config::parse(
+            "[provisioner]\nkind = \"shell\"\nstart = \"./up.sh\"\nstop = \"./down.sh\"",
+        )?;
+        assert!(matches!(
+            config.provisioner,
+            ProvisionerConfig::Shell { status: None, .. }
+        ));
+        assert!(Config::parse("[provisioner]\nkind = \"shell\"\nstart = \"./up.sh\"").is_err());
+        assert!(Config::parse("[provisioner]\nkind = \"docker\"").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_fields() {
+        let err = Config::parse("[backend]\naddress = \"nope\"").unwrap_err();
+        assert!(err.to_string().contains("backend.address"));
+        let err = Config::parse("[idle]\npoll_interval_secs = 0").unwrap_err();
+        assert!(err.to_string().contains("idle.poll_interval_secs"));
+        let err = Config::parse("[limits]\npings_per_minute = 0").unwrap_err();
+        assert!(err.to_string().contains("limits.pings_per_minute"));
+        let err = Config::parse("[limits]\nmax_connections = 0").unwrap_err();
+        assert!(err.to_string().contains("limits.max_connections"));
+        let err = Config::parse("[limits]\nmax_frame_length = 0").unwrap_err();
+        assert!(err.to_string().contains("limits.max_frame_length"));
+        let err = Config::parse("[backend]\nversion = \"1.99\"").unwrap_err();
+        assert!(err.to_string().contains("backend.version"));
+        let err = Config::parse("[server]\nonline_mode = true\nhold_logins = true").unwrap_err();
+        assert!(err.to_string().contains("server.hold_logins"));
+        let err = Config::parse("[proxy]\nforwarding = \"velocity\"").unwrap_err();
+        assert!(err.to_string().contains("proxy.forwarding_secret"));
+        let err =
+            Config::parse("[proxy]\nmode = \"raw\"\nforwarding = \"bungeecord\"").unwrap_err();
+        assert!(err.to_string().contains("proxy.forwarding"));
+        let err = Config::parse("[proxy]\nmode = \"raw\"").unwrap_err();
+        assert!(err.to_string().contains("proxy.mode"));
+        assert!(Config::parse("[proxy]\nmode = \"raw\"\n[rcon]\npassword = \"x\"").is_ok());
+        let err = Config::parse("[proxy_protocol]\naccept = true").unwrap_err();
+        assert!(err.to_string().contains("proxy_protocol.trusted"));
+        let config = Config::parse("[proxy_protocol]\naccept = true\ntrusted = [\"10.0.0.1\"]");
+        assert!(config
+            .unwrap()
+            .proxy_protocol
+            .trusts("10.0.0.1".parse().unwrap()));
+        assert!(Config::parse("[proxy_protocol]\ntrusted = [\"balancer\"]").is_err());
+        assert!(Config::parse("[server]\nmax_players = -1").is_err());
+        assert!(Config::parse("[server]\nmotd_typo = \"hi\"").is_err());
+    }
+}
@@ -1 +1,122 @@
-pub type Error = Box<dyn std::error::Error + Send + Sync>;
+use std::{fmt, io};
+
+/// Everything that can go wrong in the facade, split up by what the caller might want to do
+/// about it
+#[derive(Debug)]
+pub enum Error {
+    /// The socket, file or child process underneath failed
+    Io(io::Error),
+    /// Bytes that don't decode: a VarInt that's too long, bad utf-8, a length that doesn't fit...
+    Framing(String),
+    /// A packet id that doesn't belong where we found it
+    UnknownPacket(i32),
+    /// The other side said something well formed that doesn't fit the conversation
+    Protocol(String),
+    /// Our credentials were rejected, which retrying won't fix
+    Auth(String),
+    /// Something took too long
+    Timeout(String),
+    /// The real server isn't there, or won't start or stop
+    BackendUnavailable(String),
+    /// A bad config file, command line or something they point at
+    Config(String),
+    /// Something of our own that shouldn't ever fail, like making a key
+    Internal(String),
+}
+
+impl Error {
+    pub fn framing(message: impl Into<String>) -> Self {
+        Error::Framing(message.into())
+    }
+
+    pub fn protocol(message: impl Into<String>) -> Self {
+        Error::Protocol(message.into())
+    }
+
+    pub fn backend(message: impl Into<String>) -> Self {
+        Error::BackendUnavailable(message.into())
+    }
+
+    pub fn config(message: impl Into<String>) -> Self {
+        Error::Config(message.into())
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        Error::Internal(message.into())
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Framing(message) => write!(f, "Malformed data: {}", message),
+            Error::UnknownPacket(id) => write!(f, "Unknown packet id {:#04x}", id),
+            Error::Protocol(message) => write!(f, "{}", message),
+            Error::Auth(message) => write!(f, "{}", message),
+            Error::Timeout(message) => write!(f, "Timed out: {}", message),
+            Error::BackendUnavailable(message) => write!(f, "{}", message),
+            Error::Config(message) => write!(f, "{}", message),
+            Error::Internal(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+// Decoding failures from the libraries we lean on all mean the bytes were bad
+
+impl From<std::str::Utf8Error> for Error {
+    fn from(e: std::str::Utf8Error) -> Self {
+        Error::framing(e.to_string())
+    }
+}
+
+impl From<std::string::FromUtf8Error> for Error {
+    fn from(e: std::string::FromUtf8Error) -> Self {
+        Error::framing(e.to_string())
+    }
+}
+
+impl From<std::string::FromUtf16Error> for Error {
+    fn from(e: std::string::FromUtf16Error) -> Self {
+        Error::framing(e.to_string())
+    }
+}
+
+impl From<std::num::TryFromIntError> for Error {
+    fn from(e: std::num::TryFromIntError) -> Self {
+        Error::framing(e.to_string())
+    }
+}
+
+impl From<std::array::TryFromSliceError> for Error {
+    fn from(e: std::array::TryFromSliceError) -> Self {
+        Error::framing(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::framing(e.to_string())
+    }
+}
+
+impl From<toml::de::Error> for Error {
+    fn from(e: toml::de::Error) -> Self {
+        Error::config(e.to_string())
+    }
+}
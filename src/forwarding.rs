@@ -0,0 +1,164 @@
+/*
+    Telling the real server who is logging in, since otherwise every player comes from the
+    facade's address. The facade doesn't check anyone's identity, so like an offline mode proxy
+    it forwards the UUID the name would have in offline mode.
+        BungeeCord (legacy): the handshake's server address becomes
+            host\0client ip\0uuid without dashes\0properties as json
+        and Spigot (`bungeecord: true`) takes the rest from there. Nothing is signed, so the real
+        server has to be unreachable except through the facade.
+        Velocity (modern): the real server asks for the player with a login plugin request on
+        velocity:player_info. The answer is signed with a secret both sides know:
+            HMAC-SHA256 of the rest (32 bytes), VarInt version, String client ip, UUID,
+            String name, VarInt property count
+        which Paper checks before taking the rest.
+*/
+
+use crate::error::Error;
+use crate::server::codec::{Decode, Encode, Packet, VarInt};
+use crate::server::framing::Framing;
+use crate::server::intercept::Intercepted;
+use crate::server::packet::{LoginPluginRequest, LoginPluginResponse};
+use crate::server::write::atom::write_varint;
+use hmac::{Hmac, Mac};
+use md5::{Digest, Md5};
+use sha2::Sha256;
+use std::convert::TryInto;
+use std::net::IpAddr;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+const VELOCITY_CHANNEL: &str = "velocity:player_info";
+// The first version, which every version of Paper that does Velocity forwarding understands
+const VELOCITY_VERSION: i32 = 1;
+
+/// The UUID a player gets in offline mode, a version 3 UUID of "OfflinePlayer:<name>"
+pub fn offline_uuid(name: &str) -> u128 {
+    let mut hash: [u8; 16] = Md5::digest(format!("OfflinePlayer:{}", name)).into();
+    hash[6] = (hash[6] & 0x0f) | 0x30;
+    hash[8] = (hash[8] & 0x3f) | 0x80;
+    u128::from_be_bytes(hash)
+}
+
+/// The server address a BungeeCord handshake has for `name` connecting from `ip`
+pub fn bungeecord_address(server_address: &str, ip: IpAddr, name: &str) -> String {
+    // Forge clients tack "\0FML\0" onto the host, which would push everything else along
+    let host = server_address.split('\0').next().unwrap_or_default();
+    format!(
+        "{}\0{}\0{:032x}\0[]",
+        host,
+        ip.to_canonical(),
+        offline_uuid(name)
+    )
+}
+
+/// What to replay to the real server instead of what the client sent: the same login, with
+/// a BungeeCord handshake
+pub async fn bungeecord_login(login: &Intercepted) -> Result<Vec<u8>, Error> {
+    let mut handshake = login.handshake.clone();
+    let name = login.player().unwrap_or_default();
+    handshake.server_address = bungeecord_address(&handshake.server_address, login.peer.ip(), name);
+    let framing = Framing::default();
+    let mut replayed = vec![];
+    framing.write(&handshake, &mut replayed).await?;
+    if let Some(login_start) = &login.login_start {
+        framing.write(login_start, &mut replayed).await?;
+    }
+    Ok(replayed)
+}
+
+/// Velocity's player info for `name` connecting from `ip`, signed with `secret`
+pub fn velocity_player_info(secret: &[u8], ip: IpAddr, name: &str) -> Result<Vec<u8>, Error> {
+    let mut info = vec![];
+    VarInt::from(VELOCITY_VERSION).encode(&mut info)?;
+    ip.to_canonical().to_string().encode(&mut info)?;
+    offline_uuid(name).encode(&mut info)?;
+    name.to_owned().encode(&mut info)?;
+    VarInt::from(0).encode(&mut info)?; // no properties
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes keys of any length");
+    mac.update(&info);
+    Ok([mac.finalize().into_bytes().to_vec(), info].concat())
+}
+
+/// Answer the real server's request for player info, which comes before anything else it sends
+/// during the login. If it sends something else it isn't expecting Velocity forwarding, so that
+/// gets passed on to the client and the login carries on without it.
+pub async fn answer_velocity(
+    backend: &mut TcpStream,
+    client: &mut TcpStream,
+    secret: &[u8],
+    ip: IpAddr,
+    name: &str,
+) -> Result<(), Error> {
+    // Nothing is compressed until after the login plugin request
+    let framing = Framing::default();
+    let (id, mut cursor) = framing.read_frame(backend).await?;
+    if id == LoginPluginRequest::ID {
+        let start = cursor.position();
+        let request = LoginPluginRequest::decode(&mut cursor)?;
+        if request.channel == VELOCITY_CHANNEL {
+            debug!("Forwarding {} from {} to the real server", name, ip);
+            let response = LoginPluginResponse {
+                message_id: request.message_id,
+                understood: true,
+                data: velocity_player_info(secret, ip, name)?,
+            };
+            return framing.write(&response, backend).await;
+        }
+        cursor.set_position(start);
+    }
+    warn!(
+        "The real server didn't ask who {} is, is its Velocity forwarding turned on?",
+        name
+    );
+    let frame = cursor.into_inner();
+    let mut passed_on = vec![];
+    write_varint(frame.len().try_into()?, &mut passed_on)?;
+    passed_on.extend(frame);
+    client.write_all(&passed_on).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_offline_uuid() {
+        assert_eq!(
+            0xb50ad385_829d_3141_a216_7e7d7539ba7f,
+            offline_uuid("Notch")
+        );
+    }
+
+    #[test]
+    fn test_bungeecord_address() {
+        let ip = "203.0.113.7".parse().unwrap();
+        assert_eq!(
+            "play.example.com\x00203.0.113.7\x00b50ad385829d3141a2167e7d7539ba7f\x00[]",
+            bungeecord_address("play.example.com", ip, "Notch")
+        );
+        // Forge's marker gets dropped, and dual stack sockets' IPv4 addresses look like IPv4
+        let mapped = "::ffff:203.0.113.7".parse().unwrap();
+        assert_eq!(
+            bungeecord_address("play.example.com", ip, "Notch"),
+            bungeecord_address("play.example.com\0FML\0", mapped, "Notch")
+        );
+    }
+
+    #[test]
+    fn test_velocity_player_info() -> Result<(), Error> {
+        let ip = "203.0.113.7".parse().unwrap();
+        let info = velocity_player_info(b"hunter2", ip, "Notch")?;
+        let (signature, mut rest) = info.split_at(32);
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"hunter2").unwrap();
+        mac.update(rest);
+        mac.verify_slice(signature).unwrap();
+        assert_eq!(1, i32::from(VarInt::decode(&mut rest)?));
+        assert_eq!("203.0.113.7", String::decode(&mut rest)?);
+        assert_eq!(offline_uuid("Notch"), u128::decode(&mut rest)?);
+        assert_eq!("Notch", String::decode(&mut rest)?);
+        assert_eq!(0, i32::from(VarInt::decode(&mut rest)?));
+        assert!(rest.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,206 @@
+/*
+    Watches the real server over rcon to decide when it's time to shut it down.
+    Every poll runs `list`; once nobody has been online for the whole grace period, the server
+    gets `save-all` and `stop` and we report it as idle.
+*/
+
+use crate::error::Error;
+use crate::rcon::{self, Connection};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::time::sleep;
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct PlayerList {
+    pub online: u32,
+    pub max: u32,
+    pub names: Vec<String>,
+}
+
+/// Parse the response to `list`, which looks like either
+/// "There are 1 of a max of 20 players online: Steve" (1.13+) or
+/// "There are 1/20 players online:Steve" (older)
+pub fn parse_list(response: &str) -> Result<PlayerList, Error> {
+    let bad = || Error::protocol(format!("Unexpected list response \"{}\"", response));
+    let rest = response.trim().strip_prefix("There are ").ok_or_else(bad)?;
+    let (counts, names) = rest.split_once("players online:").ok_or_else(bad)?;
+    let counts = counts.trim();
+    let (online, max) = counts
+        .split_once(" of a max of ")
+        .or_else(|| counts.split_once('/'))
+        .ok_or_else(bad)?;
+    let names = names
+        .split(',')
+        .map(|name| name.trim().to_owned())
+        .filter(|name| !name.is_empty())
+        .collect();
+    Ok(PlayerList {
+        online: online.trim().parse().map_err(|_| bad())?,
+        max: max.trim().parse().map_err(|_| bad())?,
+        names,
+    })
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Activity {
+    /// Somebody is online
+    Occupied,
+    /// Nobody is online, but not for long enough to shut down
+    Empty,
+    /// Nobody has been online for the whole grace period
+    Idle,
+}
+
+// Keeps track of how long the server has been empty
+struct EmptyTimer {
+    grace_period: Duration,
+    empty_since: Option<Instant>,
+}
+
+impl EmptyTimer {
+    fn observe(&mut self, online: u32, now: Instant) -> Activity {
+        if online > 0 {
+            self.empty_since = None;
+            return Activity::Occupied;
+        }
+        let empty_since = *self.empty_since.get_or_insert(now);
+        if now.duration_since(empty_since) >= self.grace_period {
+            Activity::Idle
+        } else {
+            Activity::Empty
+        }
+    }
+}
+
+pub struct IdleWatcher {
+    pub rcon_addr: String,
+    pub password: String,
+    pub poll_interval: Duration,
+    pub grace_period: Duration,
+}
+
+impl IdleWatcher {
+    /// Poll until the server has been empty for the grace period, reporting every change in
+    /// activity along the way. Stops the server before returning, unless rcon rejected our
+    /// password, since then we can't tell what's going on at all.
+    pub async fn run(self, report: UnboundedSender<Activity>) -> Result<(), Error> {
+        let mut timer = EmptyTimer {
+            grace_period: self.grace_period,
+            empty_since: None,
+        };
+        let mut conn = None;
+        let mut last = None;
+        loop {
+            // A server we can't reach has nobody playing on it, so failures count as empty.
+            // That way a crashed server still gets cleaned up.
+            let online = match self.poll(&mut conn).await {
+                Ok(list) => list.online,
+                Err(e @ Error::Auth(_)) => return Err(e),
+                Err(e) => {
+                    warn!("Couldn't list players on the real server: {}", e);
+                    conn = None;
+                    0
+                }
+            };
+            let activity = timer.observe(online, Instant::now());
+            if last != Some(activity) {
+                debug!("Real server is {:?} ({} online)", activity, online);
+                let _ = report.send(activity);
+                last = Some(activity);
+            }
+            if activity == Activity::Idle {
+                break;
+            }
+            sleep(self.poll_interval).await;
+        }
+        info!(
+            "Nobody online for {:?}, stopping the real server",
+            self.grace_period
+        );
+        if let Err(e) = self.shut_down(&mut conn).await {
+            warn!("Couldn't stop the real server over rcon: {}", e);
+        }
+        Ok(())
+    }
+
+    async fn connection<'a>(
+        &self,
+        conn: &'a mut Option<Connection<TcpStream>>,
+    ) -> Result<&'a mut Connection<TcpStream>, Error> {
+        if conn.is_none() {
+            *conn = Some(rcon::connect(&self.rcon_addr, self.password.clone()).await?);
+        }
+        Ok(conn.as_mut().unwrap())
+    }
+
+    async fn poll(&self, conn: &mut Option<Connection<TcpStream>>) -> Result<PlayerList, Error> {
+        let response = self.connection(conn).await?.run_command("list").await?;
+        parse_list(&response)
+    }
+
+    async fn shut_down(&self, conn: &mut Option<Connection<TcpStream>>) -> Result<(), Error> {
+        let conn = self.connection(conn).await?;
+        conn.run_command("save-all").await?;
+        // The server can hang up before answering, which is fine since it's going down anyway
+        if let Err(e) = conn.run_command("stop").await {
+            debug!("Stop command didn't get a response: {}", e);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_list() -> Result<(), Error> {
+        assert_eq!(
+            PlayerList {
+                online: 2,
+                max: 20,
+                names: vec!["Steve".to_owned(), "Alex".to_owned()]
+            },
+            parse_list("There are 2 of a max of 20 players online: Steve, Alex")?
+        );
+        assert_eq!(
+            PlayerList {
+                online: 0,
+                max: 10,
+                names: vec![]
+            },
+            parse_list("There are 0/10 players online:")?
+        );
+        assert!(parse_list("Unknown command").is_err());
+        assert!(parse_list("There are lots of a max of 20 players online:").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_empty_timer() {
+        let start = Instant::now();
+        let mut timer = EmptyTimer {
+            grace_period: Duration::from_secs(60),
+            empty_since: None,
+        };
+        assert_eq!(Activity::Empty, timer.observe(0, start));
+        assert_eq!(
+            Activity::Empty,
+            timer.observe(0, start + Duration::from_secs(30))
+        );
+        // Someone joining resets the clock
+        assert_eq!(
+            Activity::Occupied,
+            timer.observe(1, start + Duration::from_secs(40))
+        );
+        assert_eq!(
+            Activity::Empty,
+            timer.observe(0, start + Duration::from_secs(61))
+        );
+        assert_eq!(
+            Activity::Idle,
+            timer.observe(0, start + Duration::from_secs(121))
+        );
+    }
+}
@@ -1,20 +1,91 @@
-use tokio::net::TcpListener;
-
+use crate::cli::Command;
+use crate::config::Config;
 use crate::error::Error;
-use crate::server::fake_server::run_fake_server;
-use std::env;
+use crate::server::{client, fake_server::run_fake_server};
+use crate::supervisor::Supervisor;
+use std::{env, process, sync::Arc};
 
 #[macro_use]
 extern crate log;
 
+mod allowlist;
+mod cli;
+mod config;
 mod error;
+mod forwarding;
+mod idle;
+mod provisioner;
+mod proxy;
+mod proxy_protocol;
 mod rcon;
 mod server;
-mod proxy;
+mod supervisor;
 mod util;
+mod wake;
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
-    env_logger::init();
+    let args = match cli::parse(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, cli::USAGE);
+            process::exit(2);
+        }
+    };
+
+    let mut logger = env_logger::Builder::new();
+    logger.filter_level(args.log_level);
+    if let Ok(filters) = env::var("RUST_LOG") {
+        logger.parse_filters(&filters);
+    }
+    logger.init();
+
+    let mut config = match &args.config_path {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+
+    match args.command {
+        Command::Run { bind } => {
+            if let Some(bind) = bind {
+                config.server.listen = bind;
+            }
+            Supervisor::bind(config).await?.run().await?;
+        }
+        Command::Serve { bind } => {
+            if let Some(bind) = bind {
+                config.server.listen = bind;
+            }
+            run_fake_server(Arc::new(config)).await?;
+        }
+        Command::Proxy { bind, backend } => {
+            let bind = bind.unwrap_or(config.server.listen);
+            let backend = backend.or(config.backend.address).ok_or_else(|| {
+                Error::config("--backend or backend.address is required to proxy")
+            })?;
+            proxy::run_proxy(&bind, &backend, config.proxy_protocol, config.proxy).await?;
+        }
+        Command::RconExec {
+            addr,
+            password,
+            command,
+        } => {
+            let addr = addr.unwrap_or(config.rcon.address);
+            let password = password.or(config.rcon.password).ok_or_else(|| {
+                Error::config(format!(
+                    "--password, {} or rcon.password is required for rcon",
+                    cli::PASSWORD_ENV
+                ))
+            })?;
+            let mut conn = rcon::connect(&addr, password).await?;
+            println!("{}", conn.run_command(&command).await?);
+        }
+        Command::Ping { addr } => {
+            let result = client::ping(&addr).await?;
+            println!("{}", result.status);
+            info!("Ping took {}ms", result.latency.as_millis());
+        }
+        Command::Help => print!("{}", cli::USAGE),
+    }
     Ok(())
 }
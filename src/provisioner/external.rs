@@ -0,0 +1,165 @@
+use super::{BackendStatus, Provisioner};
+use crate::config::RconConfig;
+use crate::error::Error;
+use crate::rcon;
+use crate::server::client::ping;
+use futures::future::{BoxFuture, FutureExt};
+use std::time::Duration;
+use tokio::time::{sleep, timeout};
+
+const STOP_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// A server someone else starts (a systemd unit, a VM that boots on its own...). We can still
+/// stop it over rcon, and tell whether it's up by pinging it.
+pub struct External {
+    address: String,
+    rcon_address: String,
+    rcon_password: Option<String>,
+    stop_timeout: Duration,
+}
+
+impl External {
+    pub fn new(address: String, rcon: &RconConfig, stop_timeout: Duration) -> Self {
+        External {
+            address,
+            rcon_address: rcon.address.clone(),
+            rcon_password: rcon.password.clone(),
+            stop_timeout,
+        }
+    }
+
+    async fn stop_over_rcon(&self) -> Result<(), Error> {
+        match timeout(self.stop_timeout, self.stop_and_wait()).await {
+            Ok(result) => result,
+            Err(_) => Err(Error::Timeout(format!(
+                "waiting {:?} for the real server to go down",
+                self.stop_timeout
+            ))),
+        }
+    }
+
+    async fn stop_and_wait(&self) -> Result<(), Error> {
+        // The idle watcher has usually stopped it already
+        if ping(&self.address).await.is_err() {
+            return Ok(());
+        }
+        let password = self
+            .rcon_password
+            .clone()
+            .ok_or_else(|| Error::config("No rcon password configured"))?;
+        let mut conn = rcon::connect(&self.rcon_address, password).await?;
+        // The server can hang up on us before answering, which is fine as long as it goes down
+        if let Err(e) = conn.run_command("stop").await {
+            debug!("Stop command didn't get a response: {}", e);
+        }
+        while ping(&self.address).await.is_ok() {
+            sleep(STOP_POLL_INTERVAL).await;
+        }
+        Ok(())
+    }
+}
+
+impl Provisioner for External {
+    fn start(&self) -> BoxFuture<'_, Result<(), Error>> {
+        info!("Waiting for something else to start the real server");
+        futures::future::ready(Ok(())).boxed()
+    }
+
+    fn stop(&self) -> BoxFuture<'_, Result<(), Error>> {
+        self.stop_over_rcon().boxed()
+    }
+
+    fn status(&self) -> BoxFuture<'_, Result<BackendStatus, Error>> {
+        async move {
+            Ok(match ping(&self.address).await {
+                Ok(_) => BackendStatus::Running,
+                Err(_) => BackendStatus::Stopped,
+            })
+        }
+        .boxed()
+    }
+
+    fn address(&self) -> BoxFuture<'_, Result<String, Error>> {
+        futures::future::ready(Ok(self.address.clone())).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+    use crate::server::fake_server::{handle_connection, Listing};
+    use crate::server::limits::Limiter;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    // A real server that answers pings, and an rcon port that goes along with logging in. If
+    // `obeys` it goes down when told to stop. Returns the server's address and rcon's.
+    async fn fake_server(obeys: bool) -> (String, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let config = ServerConfig::default();
+            loop {
+                let (socket, peer) = listener.accept().await.unwrap();
+                let _ = handle_connection(
+                    socket,
+                    peer,
+                    &config,
+                    &Listing::default(),
+                    &Limiter::default(),
+                )
+                .await;
+            }
+        });
+        let rcon = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let rcon_addr = rcon.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let mut socket = rcon.accept().await.unwrap().0;
+            // The login, then the command
+            loop {
+                let length = socket.read_i32_le().await.unwrap();
+                let mut packet = vec![0; length as usize];
+                socket.read_exact(&mut packet).await.unwrap();
+                if packet[8..].starts_with(b"stop") {
+                    if obeys {
+                        server.abort();
+                    }
+                    return;
+                }
+                // The same request id back, as an auth response
+                let mut response = 10_i32.to_le_bytes().to_vec();
+                response.extend(&packet[..4]);
+                response.extend(&2_i32.to_le_bytes());
+                response.extend(&[0, 0]);
+                socket.write_all(&response).await.unwrap();
+            }
+        });
+        (addr, rcon_addr)
+    }
+
+    fn external(addr: String, rcon_addr: String, stop_timeout: Duration) -> External {
+        let rcon = RconConfig {
+            address: rcon_addr,
+            password: Some("hunter2".to_owned()),
+        };
+        External::new(addr, &rcon, stop_timeout)
+    }
+
+    #[tokio::test]
+    async fn test_stop() -> Result<(), Error> {
+        let (addr, rcon_addr) = fake_server(true).await;
+        let external = external(addr, rcon_addr, Duration::from_secs(10));
+        assert_eq!(BackendStatus::Running, external.status().await?);
+        external.stop().await?;
+        assert_eq!(BackendStatus::Stopped, external.status().await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stop_timeout() {
+        let (addr, rcon_addr) = fake_server(false).await;
+        let external = external(addr, rcon_addr, Duration::from_millis(500));
+        assert!(matches!(external.stop().await, Err(Error::Timeout(_))));
+    }
+}
@@ -0,0 +1,156 @@
+use super::{BackendStatus, Provisioner};
+use crate::error::Error;
+use futures::future::{BoxFuture, FutureExt};
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::process::{Child, Command};
+use tokio::sync::Mutex;
+use tokio::time::timeout;
+
+/// Runs the server as a child process, `java -jar server.jar` by default. Stopping it types
+/// `stop` into its console, the same as an admin would.
+pub struct LocalProcess {
+    command: String,
+    args: Vec<String>,
+    working_dir: Option<String>,
+    address: String,
+    stop_timeout: Duration,
+    child: Mutex<Option<Child>>,
+}
+
+impl LocalProcess {
+    pub fn new(
+        command: String,
+        args: Vec<String>,
+        working_dir: Option<String>,
+        address: String,
+        stop_timeout: Duration,
+    ) -> Self {
+        LocalProcess {
+            command,
+            args,
+            working_dir,
+            address,
+            stop_timeout,
+            child: Mutex::new(None),
+        }
+    }
+
+    async fn spawn(&self) -> Result<(), Error> {
+        let mut child = self.child.lock().await;
+        if let Some(running) = child.as_mut() {
+            if running.try_wait()?.is_none() {
+                debug!("Real server process is already running");
+                return Ok(());
+            }
+        }
+        let mut command = Command::new(&self.command);
+        command
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .kill_on_drop(true);
+        if let Some(dir) = &self.working_dir {
+            command.current_dir(dir);
+        }
+        info!("Starting {} {}", self.command, self.args.join(" "));
+        *child = Some(command.spawn()?);
+        Ok(())
+    }
+
+    async fn shutdown(&self) -> Result<(), Error> {
+        let mut child = match self.child.lock().await.take() {
+            Some(child) => child,
+            None => return Ok(()),
+        };
+        if let Some(stdin) = child.stdin.as_mut() {
+            // If this fails the process is probably already gone, which wait will tell us
+            if let Err(e) = stdin.write_all(b"stop\n").await {
+                debug!("Couldn't send stop to the real server: {}", e);
+            }
+        }
+        match timeout(self.stop_timeout, child.wait()).await {
+            Ok(exit) => info!("Real server exited with {}", exit?),
+            Err(_) => {
+                warn!("Real server didn't stop in time, killing it");
+                child.kill().await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn check(&self) -> Result<BackendStatus, Error> {
+        let mut child = self.child.lock().await;
+        let running = match child.as_mut() {
+            Some(running) => running.try_wait()?.is_none(),
+            None => false,
+        };
+        Ok(if running {
+            BackendStatus::Running
+        } else {
+            BackendStatus::Stopped
+        })
+    }
+}
+
+impl Provisioner for LocalProcess {
+    fn start(&self) -> BoxFuture<'_, Result<(), Error>> {
+        self.spawn().boxed()
+    }
+
+    fn stop(&self) -> BoxFuture<'_, Result<(), Error>> {
+        self.shutdown().boxed()
+    }
+
+    fn status(&self) -> BoxFuture<'_, Result<BackendStatus, Error>> {
+        self.check().boxed()
+    }
+
+    fn address(&self) -> BoxFuture<'_, Result<String, Error>> {
+        futures::future::ready(Ok(self.address.clone())).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Stands in for a minecraft server: runs until it reads "stop" on stdin
+    const STUB_SERVER: &str = r#"while read line; do [ "$line" = stop ] && exit 0; done"#;
+
+    fn stub() -> LocalProcess {
+        LocalProcess::new(
+            "sh".to_owned(),
+            vec!["-c".to_owned(), STUB_SERVER.to_owned()],
+            None,
+            "127.0.0.1:25565".to_owned(),
+            Duration::from_secs(5),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_start_stop() -> Result<(), Error> {
+        let provisioner = stub();
+        assert_eq!(BackendStatus::Stopped, provisioner.status().await?);
+        provisioner.start().await?;
+        assert_eq!(BackendStatus::Running, provisioner.status().await?);
+        provisioner.stop().await?;
+        assert_eq!(BackendStatus::Stopped, provisioner.status().await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_kill_after_timeout() -> Result<(), Error> {
+        let provisioner = LocalProcess::new(
+            "sleep".to_owned(),
+            vec!["30".to_owned()],
+            None,
+            "127.0.0.1:25565".to_owned(),
+            Duration::from_millis(50),
+        );
+        provisioner.start().await?;
+        provisioner.stop().await?; // sleep ignores stdin, so this has to kill it
+        assert_eq!(BackendStatus::Stopped, provisioner.status().await?);
+        Ok(())
+    }
+}
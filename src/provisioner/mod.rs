@@ -0,0 +1,80 @@
+mod external;
+mod local;
+mod shell;
+
+pub use self::external::External;
+pub use self::local::LocalProcess;
+pub use self::shell::ShellCommand;
+
+use crate::config::{Config, ProvisionerConfig};
+use crate::error::Error;
+use futures::future::BoxFuture;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum BackendStatus {
+    Stopped,
+    Running,
+}
+
+/// Something that can bring the real server up and down.
+///
+/// These return boxed futures rather than being async fns so the supervisor can pick an
+/// implementation from the config at runtime.
+pub trait Provisioner: Send + Sync {
+    /// Start the server. Returning doesn't mean it's accepting players yet, just that it's on its way.
+    fn start(&self) -> BoxFuture<'_, Result<(), Error>>;
+    /// Stop the server, returning once it's down
+    fn stop(&self) -> BoxFuture<'_, Result<(), Error>>;
+    fn status(&self) -> BoxFuture<'_, Result<BackendStatus, Error>>;
+    /// Where players should be proxied to. Only guaranteed to be right after `start`.
+    fn address(&self) -> BoxFuture<'_, Result<String, Error>>;
+}
+
+pub fn from_config(config: &Config) -> Result<Arc<dyn Provisioner>, Error> {
+    let backend_addr = config.backend.address.clone();
+    let require_addr = || {
+        backend_addr
+            .clone()
+            .ok_or_else(|| Error::config("backend.address is required for this provisioner"))
+    };
+    Ok(match &config.provisioner {
+        ProvisionerConfig::External { stop_timeout_secs } => Arc::new(External::new(
+            require_addr()?,
+            &config.rcon,
+            Duration::from_secs(*stop_timeout_secs),
+        )),
+        ProvisionerConfig::Local {
+            command,
+            args,
+            working_dir,
+            stop_timeout_secs,
+        } => Arc::new(LocalProcess::new(
+            command.clone(),
+            args.clone(),
+            working_dir.clone(),
+            require_addr()?,
+            Duration::from_secs(*stop_timeout_secs),
+        )),
+        ProvisionerConfig::Shell {
+            start,
+            stop,
+            status,
+            address,
+        } => {
+            if address.is_none() && backend_addr.is_none() {
+                return Err(Error::config(
+                    "The shell provisioner needs either provisioner.address or backend.address",
+                ));
+            }
+            Arc::new(ShellCommand::new(
+                start.clone(),
+                stop.clone(),
+                status.clone(),
+                address.clone(),
+                backend_addr,
+            ))
+        }
+    })
+}
@@ -0,0 +1,150 @@
+use super::{BackendStatus, Provisioner};
+use crate::error::Error;
+use futures::future::{BoxFuture, FutureExt};
+use std::sync::Mutex;
+use tokio::process::Command;
+
+/// Runs user-supplied shell commands, for anything the facade doesn't know how to start itself
+/// (cloud VMs, containers, ...). The commands are run with `sh -c`, and should only exit once
+/// their job is done.
+pub struct ShellCommand {
+    start: String,
+    stop: String,
+    status: Option<String>,
+    address_command: Option<String>,
+    address: Option<String>,
+    // Without a status command, the best we can do is remember what we last did
+    last_status: Mutex<BackendStatus>,
+}
+
+impl ShellCommand {
+    pub fn new(
+        start: String,
+        stop: String,
+        status: Option<String>,
+        address_command: Option<String>,
+        address: Option<String>,
+    ) -> Self {
+        ShellCommand {
+            start,
+            stop,
+            status,
+            address_command,
+            address,
+            last_status: Mutex::new(BackendStatus::Stopped),
+        }
+    }
+
+    async fn run_and_remember(&self, script: &str, status: BackendStatus) -> Result<(), Error> {
+        run(script).await?;
+        *self.last_status.lock().unwrap() = status;
+        Ok(())
+    }
+
+    async fn check(&self) -> Result<BackendStatus, Error> {
+        let script = match &self.status {
+            Some(script) => script,
+            None => return Ok(*self.last_status.lock().unwrap()),
+        };
+        let exit = Command::new("sh").arg("-c").arg(script).status().await?;
+        Ok(if exit.success() {
+            BackendStatus::Running
+        } else {
+            BackendStatus::Stopped
+        })
+    }
+
+    async fn resolve_address(&self) -> Result<String, Error> {
+        match (&self.address_command, &self.address) {
+            (Some(script), _) => {
+                let address = run(script).await?;
+                let address = address.trim();
+                if address.is_empty() {
+                    return Err(Error::backend("Address command didn't print an address"));
+                }
+                Ok(address.to_owned())
+            }
+            (None, Some(address)) => Ok(address.clone()),
+            (None, None) => Err(Error::config("No address configured for the real server")),
+        }
+    }
+}
+
+// Run a script, returning its stdout if it exits successfully
+async fn run(script: &str) -> Result<String, Error> {
+    debug!("Running `{}`", script);
+    let output = Command::new("sh").arg("-c").arg(script).output().await?;
+    if !output.status.success() {
+        return Err(Error::backend(format!(
+            "`{}` failed with {}: {}",
+            script,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+impl Provisioner for ShellCommand {
+    fn start(&self) -> BoxFuture<'_, Result<(), Error>> {
+        self.run_and_remember(&self.start, BackendStatus::Running)
+            .boxed()
+    }
+
+    fn stop(&self) -> BoxFuture<'_, Result<(), Error>> {
+        self.run_and_remember(&self.stop, BackendStatus::Stopped)
+            .boxed()
+    }
+
+    fn status(&self) -> BoxFuture<'_, Result<BackendStatus, Error>> {
+        self.check().boxed()
+    }
+
+    fn address(&self) -> BoxFuture<'_, Result<String, Error>> {
+        self.resolve_address().boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs};
+
+    #[tokio::test]
+    async fn test_scripts() -> Result<(), Error> {
+        let marker = env::temp_dir().join(format!("facade-shell-test-{}", std::process::id()));
+        let marker = marker.display();
+        let provisioner = ShellCommand::new(
+            format!("touch {}", marker),
+            format!("rm {}", marker),
+            Some(format!("test -f {}", marker)),
+            Some("echo 10.0.0.2:25565".to_owned()),
+            None,
+        );
+        assert_eq!(BackendStatus::Stopped, provisioner.status().await?);
+        provisioner.start().await?;
+        assert_eq!(BackendStatus::Running, provisioner.status().await?);
+        assert_eq!("10.0.0.2:25565", provisioner.address().await?);
+        provisioner.stop().await?;
+        assert_eq!(BackendStatus::Stopped, provisioner.status().await?);
+        // Stopping again fails since the marker is gone, and that should be reported
+        assert!(provisioner.stop().await.is_err());
+        let _ = fs::remove_file(marker.to_string());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_remembered_status() -> Result<(), Error> {
+        let provisioner = ShellCommand::new(
+            "true".to_owned(),
+            "true".to_owned(),
+            None,
+            None,
+            Some("10.0.0.2:25565".to_owned()),
+        );
+        provisioner.start().await?;
+        assert_eq!(BackendStatus::Running, provisioner.status().await?);
+        assert_eq!("10.0.0.2:25565", provisioner.address().await?);
+        Ok(())
+    }
+}
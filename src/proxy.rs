@@ -1,24 +1,153 @@
-use core::future;
-use std::sync::Arc;
-use futures::{future::{AbortHandle, Abortable, join, select}, pin_mut};
-use tokio::{io::copy, net::{TcpListener, TcpStream, ToSocketAddrs}, sync::watch};
-use watch::{Receiver, Sender};
+use futures::future::join;
+use tokio::{
+    io::{copy, AsyncWriteExt},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+};
 
+use crate::config::{Forwarding, ProxyConfig, ProxyProtocolConfig};
 use crate::error::Error;
+use crate::forwarding;
+use crate::proxy_protocol;
+use crate::server::framing::Framing;
+use crate::server::intercept::{intercept, Intercepted};
+use crate::server::legacy::is_legacy_ping;
+use std::net::SocketAddr;
 
 async fn proxy_to_remote(incoming: TcpStream, outgoing: TcpStream) {
     let (mut inc_reader, mut inc_writer) = incoming.into_split();
     let (mut out_reader, mut out_writer) = outgoing.into_split();
-    let write_to_outgoing = copy(&mut inc_reader, &mut out_writer);
-    let read_from_incoming = copy(&mut out_reader, &mut inc_writer);
-    join(read_from_incoming, write_to_outgoing).await;
+    // Pass half-closes along, so each side sees the other finish sending
+    let write_to_outgoing = async {
+        let result = copy(&mut inc_reader, &mut out_writer).await;
+        let _ = out_writer.shutdown().await;
+        result
+    };
+    let read_from_incoming = async {
+        let result = copy(&mut out_reader, &mut inc_writer).await;
+        let _ = inc_writer.shutdown().await;
+        result
+    };
+    let (read_result, write_result) = join(read_from_incoming, write_to_outgoing).await;
+    // Either side hanging up ends the connection, so these are only interesting when debugging
+    if let Err(e) = read_result.and(write_result) {
+        debug!("Proxied connection ended with {}", e);
+    }
+}
+
+/// Proxy a connection to `remote_addr`, sending `header` ahead of it (for a PROXY protocol
+/// header, or empty for none)
+pub async fn proxy<A: ToSocketAddrs>(
+    incoming: TcpStream,
+    remote_addr: A,
+    header: &[u8],
+) -> Result<(), Error> {
+    let mut outgoing = TcpStream::connect(remote_addr).await?;
+    outgoing.write_all(header).await?;
+    proxy_to_remote(incoming, outgoing).await;
+    Ok(())
 }
-pub async fn proxy<A: ToSocketAddrs>(incoming: TcpStream, remote_addr: A) -> Result<(), Error> {
-    let outgoing = TcpStream::connect(remote_addr).await?;
+
+/// Proxy an intercepted connection, replaying what we already read so the real server gets
+/// the whole conversation. Logins get forwarded however `config` says.
+pub async fn replay<A: ToSocketAddrs>(
+    intercepted: Intercepted,
+    remote_addr: A,
+    header: &[u8],
+    config: &ProxyConfig,
+) -> Result<(), Error> {
+    let forwarding = match intercepted.is_login() {
+        true => config.forwarding,
+        false => Forwarding::None,
+    };
+    let rewritten = match forwarding {
+        Forwarding::BungeeCord => Some(forwarding::bungeecord_login(&intercepted).await?),
+        _ => None,
+    };
+    let player = intercepted.player().unwrap_or_default().to_owned();
+    let ip = intercepted.peer.ip();
+    let (mut incoming, consumed) = intercepted.into_parts();
+    let replayed = rewritten.unwrap_or(consumed);
+    let mut outgoing = TcpStream::connect(remote_addr).await?;
+    outgoing.write_all(&[header, &replayed].concat()).await?;
+    if forwarding == Forwarding::Velocity {
+        let secret = config.forwarding_secret.as_deref().unwrap_or_default();
+        forwarding::answer_velocity(&mut outgoing, &mut incoming, secret.as_bytes(), ip, &player)
+            .await?;
+    }
     proxy_to_remote(incoming, outgoing).await;
     Ok(())
 }
 
+/// The PROXY protocol header to send the real server for `incoming`, if we're sending one
+pub fn outbound_header(
+    config: &ProxyProtocolConfig,
+    incoming: &TcpStream,
+    source: SocketAddr,
+) -> Result<Vec<u8>, Error> {
+    Ok(match config.send {
+        Some(version) => proxy_protocol::header(version, source, incoming.local_addr()?),
+        None => vec![],
+    })
+}
+
+/// Proxy a connection from `peer`, reading the handshake first so a login can be forwarded
+async fn forward(
+    socket: TcpStream,
+    peer: SocketAddr,
+    remote_addr: String,
+    header: &[u8],
+    config: &ProxyConfig,
+) -> Result<(), Error> {
+    // Old clients' pings aren't framed like anything else, so those just get passed along
+    if is_legacy_ping(&socket).await? {
+        return proxy(socket, remote_addr, header).await;
+    }
+    let mut intercepted = intercept(socket, Framing::default()).await?;
+    intercepted.peer = peer;
+    replay(intercepted, remote_addr, header, config).await
+}
+
+/// Accept connections on `addr` forever, proxying each one to `remote_addr`
+pub async fn run_proxy(
+    addr: &str,
+    remote_addr: &str,
+    config: ProxyProtocolConfig,
+    proxy_config: ProxyConfig,
+) -> Result<(), Error> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("Proxying {} to {}", addr, remote_addr);
+    loop {
+        let (mut socket, peer) = listener.accept().await?;
+        if config.accept && !config.trusts(peer.ip()) {
+            debug!(
+                "Closed a connection from {}, which isn't a trusted balancer",
+                peer
+            );
+            continue;
+        }
+        let config = config.clone();
+        let remote_addr = remote_addr.to_owned();
+        let proxy_config = proxy_config.clone();
+        tokio::spawn(async move {
+            let result = async {
+                let peer = match config.accept {
+                    true => proxy_protocol::accept(&mut socket).await?,
+                    false => peer,
+                };
+                debug!("Proxying a connection from {}", peer);
+                let header = outbound_header(&config, &socket, peer)?;
+                match proxy_config.forwarding {
+                    Forwarding::None => proxy(socket, remote_addr, &header).await,
+                    _ => forward(socket, peer, remote_addr, &header, &proxy_config).await,
+                }
+            };
+            if let Err(e) = result.await {
+                error!("{}", e);
+            }
+        });
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use tokio::io::{AsyncReadExt, AsyncWriteExt};
@@ -37,7 +166,7 @@ mod tests {
         // Start a tcp server that proxies to that server
         // Run a connection to the proxy server, send it 1, expect to get 2 back.
 
-        let mut real_listener = mk_listener().await;
+        let real_listener = mk_listener().await;
         let real_addr = real_listener.local_addr().unwrap();
 
         // The real server - adds one to the number sent
@@ -47,12 +176,12 @@ mod tests {
             stream.write_all(&(num + 1).to_be_bytes()).await.unwrap();
         });
 
-        let mut proxy_listener = mk_listener().await;
+        let proxy_listener = mk_listener().await;
         let proxy_addr = proxy_listener.local_addr().unwrap();
         // The proxy - forwards to the real address
         tokio::spawn(async move {
-            let mut stream = proxy_listener.accept().await.unwrap().0;
-            proxy(stream, real_addr).await
+            let stream = proxy_listener.accept().await.unwrap().0;
+            proxy(stream, real_addr, &[]).await
         });
 
         // Connect to the proxy and make sure that our number goes through correctly
@@ -62,4 +191,191 @@ mod tests {
         let recv_num = stream.read_i64().await.unwrap();
         assert_eq!(send_num + 1, recv_num);
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_proxy_sends_header() {
+        let real_listener = mk_listener().await;
+        let real_addr = real_listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut stream = real_listener.accept().await.unwrap().0;
+            let mut received = vec![];
+            stream.read_to_end(&mut received).await.unwrap();
+            stream.write_all(&received).await.unwrap();
+        });
+
+        let proxy_listener = mk_listener().await;
+        let proxy_addr = proxy_listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let stream = proxy_listener.accept().await.unwrap().0;
+            let config = ProxyProtocolConfig {
+                accept: false,
+                send: Some(proxy_protocol::Version::V1),
+                ..Default::default()
+            };
+            let source = "203.0.113.7:51234".parse().unwrap();
+            let header = outbound_header(&config, &stream, source).unwrap();
+            proxy(stream, real_addr, &header).await
+        });
+
+        let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut received = vec![];
+        stream.read_to_end(&mut received).await.unwrap();
+        let expected = format!(
+            "PROXY TCP4 203.0.113.7 127.0.0.1 51234 {}\r\nhello",
+            proxy_addr.port()
+        );
+        assert_eq!(expected.as_bytes(), &received[..]);
+    }
+
+    #[tokio::test]
+    async fn test_replay_handshake() {
+        use crate::server::framing::Framing;
+        use crate::server::intercept::intercept;
+        use crate::server::packet::Handshake;
+        use crate::server::write::packet::write;
+
+        // The "real server" sends back everything it gets once the client hangs up
+        let real_listener = mk_listener().await;
+        let real_addr = real_listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut stream = real_listener.accept().await.unwrap().0;
+            let mut received = vec![];
+            stream.read_to_end(&mut received).await.unwrap();
+            stream.write_all(&received).await.unwrap();
+        });
+
+        let proxy_listener = mk_listener().await;
+        let proxy_addr = proxy_listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let stream = proxy_listener.accept().await.unwrap().0;
+            let intercepted = intercept(stream, Framing::default()).await.unwrap();
+            assert!(intercepted.is_login());
+            replay(intercepted, real_addr, &[], &ProxyConfig::default()).await
+        });
+
+        let handshake = Handshake {
+            protocol_version: 754,
+            server_address: "localhost".to_owned(),
+            server_port: 25565,
+            next_state: 2,
+        };
+        let mut sent = vec![];
+        write(&handshake, &mut sent).await.unwrap();
+        sent.extend_from_slice(&[0x05, 0x00, 0x03, b'b', b'o', b'b']); // login start for "bob"
+        sent.extend_from_slice(b"and then the rest of the stream");
+
+        let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+        stream.write_all(&sent).await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut received = vec![];
+        stream.read_to_end(&mut received).await.unwrap();
+        assert_eq!(sent, received);
+    }
+
+    // Log in as bob through a forwarding proxy to `real_addr`, returning the client's end
+    async fn log_in_forwarded(real_addr: SocketAddr, config: ProxyConfig) -> TcpStream {
+        use crate::server::packet::{Handshake, LoginStart};
+
+        let proxy_listener = mk_listener().await;
+        let proxy_addr = proxy_listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, peer) = proxy_listener.accept().await.unwrap();
+            forward(stream, peer, real_addr.to_string(), &[], &config).await
+        });
+
+        let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+        let framing = Framing::default();
+        let handshake = Handshake {
+            protocol_version: 765,
+            server_address: "localhost".to_owned(),
+            server_port: 25565,
+            next_state: 2,
+        };
+        framing.write(&handshake, &mut stream).await.unwrap();
+        let login_start = LoginStart {
+            name: "bob".to_owned(),
+            rest: vec![0; 16],
+        };
+        framing.write(&login_start, &mut stream).await.unwrap();
+        stream
+    }
+
+    #[tokio::test]
+    async fn test_bungeecord_forwarding() {
+        use crate::server::packet::{Handshake, LoginStart};
+
+        let real_listener = mk_listener().await;
+        let real_addr = real_listener.local_addr().unwrap();
+        let real_server = tokio::spawn(async move {
+            let mut stream = real_listener.accept().await.unwrap().0;
+            let framing = Framing::default();
+            let handshake: Handshake = framing.read_packet(&mut stream).await.unwrap();
+            let login_start: LoginStart = framing.read_packet(&mut stream).await.unwrap();
+            (handshake.server_address, login_start.name)
+        });
+
+        let config = ProxyConfig {
+            forwarding: Forwarding::BungeeCord,
+            ..ProxyConfig::default()
+        };
+        let _stream = log_in_forwarded(real_addr, config).await;
+        let (address, name) = real_server.await.unwrap();
+        let uuid = forwarding::offline_uuid("bob");
+        assert_eq!(format!("localhost\0127.0.0.1\0{:032x}\0[]", uuid), address);
+        assert_eq!("bob", name);
+    }
+
+    #[tokio::test]
+    async fn test_velocity_forwarding() {
+        use crate::server::packet::{
+            LoginDisconnect, LoginPluginRequest, LoginPluginResponse, SetCompression,
+        };
+
+        let real_listener = mk_listener().await;
+        let real_addr = real_listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let framing = Framing::default();
+            // Asks for player info and sends back what it got
+            let mut stream = real_listener.accept().await.unwrap().0;
+            framing.read_frame(&mut stream).await.unwrap(); // handshake
+            framing.read_frame(&mut stream).await.unwrap(); // login start
+            let request = LoginPluginRequest {
+                message_id: 3,
+                channel: "velocity:player_info".to_owned(),
+                data: vec![4],
+            };
+            framing.write(&request, &mut stream).await.unwrap();
+            let response: LoginPluginResponse = framing.read_packet(&mut stream).await.unwrap();
+            assert_eq!(3, response.message_id);
+            let info = LoginDisconnect {
+                reason: base64::encode(&response.data),
+            };
+            framing.write(&info, &mut stream).await.unwrap();
+
+            // Isn't expecting Velocity at all
+            let mut stream = real_listener.accept().await.unwrap().0;
+            framing.read_frame(&mut stream).await.unwrap();
+            framing.read_frame(&mut stream).await.unwrap();
+            let compression = SetCompression { threshold: 256 };
+            framing.write(&compression, &mut stream).await.unwrap();
+        });
+
+        let config = ProxyConfig {
+            forwarding: Forwarding::Velocity,
+            forwarding_secret: Some("hunter2".to_owned()),
+            ..ProxyConfig::default()
+        };
+        let framing = Framing::default();
+        let mut stream = log_in_forwarded(real_addr, config.clone()).await;
+        let info: LoginDisconnect = framing.read_packet(&mut stream).await.unwrap();
+        let ip = "127.0.0.1".parse().unwrap();
+        let expected = forwarding::velocity_player_info(b"hunter2", ip, "bob").unwrap();
+        assert_eq!(base64::encode(expected), info.reason);
+
+        let mut stream = log_in_forwarded(real_addr, config).await;
+        let compression: SetCompression = framing.read_packet(&mut stream).await.unwrap();
+        assert_eq!(256, compression.threshold);
+    }
+}
@@ -0,0 +1,265 @@
+/*
+    HAProxy's PROXY protocol, which load balancers use to pass on where a connection really came
+    from. The header comes before anything else on the connection, in one of two forms:
+        v1: text, like "PROXY TCP4 203.0.113.7 192.0.2.1 51234 25565\r\n", at most 107 bytes
+        v2: a 12 byte signature, version and command, address family, a u16 length, then the
+            addresses (and maybe TLVs, which we skip)
+    See https://www.haproxy.org/download/2.0/doc/proxy-protocol.txt
+*/
+
+use crate::error::Error;
+use serde::Deserialize;
+use std::convert::TryInto;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+const V1_MAX_LENGTH: usize = 107;
+// The balancer sends the header as soon as it connects, so there's no reason to wait long
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Version {
+    V1,
+    V2,
+}
+
+/// Where a connection really came from: the address in its PROXY header, or the other end of the
+/// socket if the header doesn't have one (like a balancer's health check)
+pub async fn accept(socket: &mut TcpStream) -> Result<SocketAddr, Error> {
+    let source = match timeout(HEADER_TIMEOUT, read_header(socket)).await {
+        Ok(source) => source?,
+        Err(_) => return Err(Error::Timeout("waiting for a PROXY header".to_owned())),
+    };
+    match source {
+        Some(source) => Ok(source),
+        None => Ok(socket.peer_addr()?),
+    }
+}
+
+/// Read a v1 or v2 header and nothing more, returning the source address if there is one
+pub async fn read_header<S: AsyncRead + Unpin>(
+    source: &mut S,
+) -> Result<Option<SocketAddr>, Error> {
+    match source.read_u8().await? {
+        b'P' => read_v1(source).await,
+        b'\r' => read_v2(source).await,
+        _ => Err(Error::protocol("Expected a PROXY protocol header")),
+    }
+}
+
+async fn read_v1<S: AsyncRead + Unpin>(source: &mut S) -> Result<Option<SocketAddr>, Error> {
+    let mut line = vec![b'P'];
+    while !line.ends_with(b"\r\n") {
+        if line.len() == V1_MAX_LENGTH {
+            return Err(Error::framing("PROXY header is too long"));
+        }
+        line.push(source.read_u8().await?);
+    }
+    let line = str::from_utf8(&line[..line.len() - 2])?;
+    let bad = || Error::framing(format!("Bad PROXY header \"{}\"", line));
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4", source, _, port, _] | ["PROXY", "TCP6", source, _, port, _] => {
+            let ip: IpAddr = source.parse().map_err(|_| bad())?;
+            let port: u16 = port.parse().map_err(|_| bad())?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(bad()),
+    }
+}
+
+async fn read_v2<S: AsyncRead + Unpin>(source: &mut S) -> Result<Option<SocketAddr>, Error> {
+    let mut signature = [0; 12];
+    signature[0] = b'\r';
+    source.read_exact(&mut signature[1..]).await?;
+    if signature != V2_SIGNATURE {
+        return Err(Error::framing("Bad PROXY v2 signature"));
+    }
+    let version_command = source.read_u8().await?;
+    let family = source.read_u8().await?;
+    let length = source.read_u16().await?;
+    let mut addresses = vec![0; length.into()];
+    source.read_exact(&mut addresses).await?;
+    if version_command >> 4 != 2 {
+        return Err(Error::framing(format!(
+            "Unknown PROXY version {}",
+            version_command >> 4
+        )));
+    }
+    // LOCAL connections come from the balancer itself
+    if version_command & 0x0F == 0 {
+        return Ok(None);
+    }
+    let too_short = || Error::framing("PROXY v2 addresses are cut short");
+    match family >> 4 {
+        // IPv4: source, destination, source port, destination port
+        1 => {
+            let ip: [u8; 4] = addresses.get(..4).ok_or_else(too_short)?.try_into()?;
+            let port = addresses.get(8..10).ok_or_else(too_short)?;
+            let port = u16::from_be_bytes(port.try_into()?);
+            Ok(Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port)))
+        }
+        // IPv6, the same way around
+        2 => {
+            let ip: [u8; 16] = addresses.get(..16).ok_or_else(too_short)?.try_into()?;
+            let port = addresses.get(32..34).ok_or_else(too_short)?;
+            let port = u16::from_be_bytes(port.try_into()?);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port)))
+        }
+        // Unix sockets and unspecified, neither of which are any use to us
+        _ => Ok(None),
+    }
+}
+
+/// A header telling the real server about a connection from `source` to `destination`
+pub fn header(version: Version, source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    let (source_ip, destination_ip) = match (source.ip(), destination.ip()) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => (source.into(), destination.into()),
+        // Both sides have to be the same family
+        (source, destination) => (IpAddr::V6(to_v6(source)), IpAddr::V6(to_v6(destination))),
+    };
+    match version {
+        Version::V1 => format!(
+            "PROXY {} {} {} {} {}\r\n",
+            if source_ip.is_ipv4() { "TCP4" } else { "TCP6" },
+            source_ip,
+            destination_ip,
+            source.port(),
+            destination.port()
+        )
+        .into_bytes(),
+        Version::V2 => {
+            let mut header = V2_SIGNATURE.to_vec();
+            // Version 2, PROXY
+            header.push(0x21);
+            let addresses = match (source_ip, destination_ip) {
+                (IpAddr::V4(source), IpAddr::V4(destination)) => {
+                    header.push(0x11); // TCP over IPv4
+                    [source.octets().to_vec(), destination.octets().to_vec()].concat()
+                }
+                (source, destination) => {
+                    header.push(0x21); // TCP over IPv6
+                    [to_v6(source).octets(), to_v6(destination).octets()].concat()
+                }
+            };
+            header.extend(&(addresses.len() as u16 + 4).to_be_bytes());
+            header.extend(addresses);
+            header.extend(&source.port().to_be_bytes());
+            header.extend(&destination.port().to_be_bytes());
+            header
+        }
+    }
+}
+
+fn to_v6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_v1() -> Result<(), Error> {
+        let mut source =
+            Cursor::new(b"PROXY TCP4 203.0.113.7 192.0.2.1 51234 25565\r\nrest".to_vec());
+        assert_eq!(
+            Some(addr("203.0.113.7:51234")),
+            read_header(&mut source).await?
+        );
+        // Nothing past the header is read
+        assert_eq!(46, source.position());
+
+        let mut source = Cursor::new(b"PROXY UNKNOWN\r\n".to_vec());
+        assert_eq!(None, read_header(&mut source).await?);
+        let mut source = Cursor::new(b"PROXY TCP4 nope 192.0.2.1 1 2\r\n".to_vec());
+        assert!(read_header(&mut source).await.is_err());
+        let mut source = Cursor::new(vec![b'P'; 200]);
+        assert!(read_header(&mut source).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_v2() -> Result<(), Error> {
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend(&[0x20, 0x00, 0x00, 0x00]);
+        assert_eq!(None, read_header(&mut Cursor::new(local)).await?);
+
+        // With a TLV on the end, which gets skipped
+        let mut with_tlv = header(
+            Version::V2,
+            addr("203.0.113.7:51234"),
+            addr("192.0.2.1:25565"),
+        );
+        with_tlv[15] += 3;
+        with_tlv.extend(&[0x04, 0x00, 0x00, 0xFF]);
+        let mut source = Cursor::new(with_tlv);
+        assert_eq!(
+            Some(addr("203.0.113.7:51234")),
+            read_header(&mut source).await?
+        );
+        assert_eq!(Ok(0xFF), source.read_u8().await.map_err(|e| e.kind()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_round_trips() -> Result<(), Error> {
+        for version in [Version::V1, Version::V2].iter() {
+            for (source, destination, expected) in [
+                ("203.0.113.7:51234", "192.0.2.1:25565", "203.0.113.7:51234"),
+                (
+                    "[2001:db8::7]:51234",
+                    "[2001:db8::1]:25565",
+                    "[2001:db8::7]:51234",
+                ),
+                // Mixed families get sent as IPv6
+                (
+                    "203.0.113.7:51234",
+                    "[2001:db8::1]:25565",
+                    "[::ffff:203.0.113.7]:51234",
+                ),
+            ]
+            .iter()
+            {
+                let header = header(*version, addr(source), addr(destination));
+                assert_eq!(
+                    Some(addr(expected)),
+                    read_header(&mut Cursor::new(header)).await?,
+                    "{:?} {}",
+                    version,
+                    source
+                );
+            }
+        }
+        assert_eq!(
+            b"PROXY TCP4 203.0.113.7 192.0.2.1 51234 25565\r\n".to_vec(),
+            header(
+                Version::V1,
+                addr("203.0.113.7:51234"),
+                addr("192.0.2.1:25565")
+            )
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_no_header() {
+        let mut source = Cursor::new(vec![0x10, 0x00]);
+        assert!(read_header(&mut source).await.is_err());
+    }
+}
@@ -1,5 +1,6 @@
 mod packet;
+#[allow(clippy::module_inception)]
 mod rcon;
 
-pub use self::packet::{read, Packet, PacketType};
+pub use self::packet::PacketType;
 pub use self::rcon::{connect, Connection};
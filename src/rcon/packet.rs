@@ -1,5 +1,4 @@
 use std::convert::{TryFrom, TryInto};
-use std::i32;
 use std::mem;
 use std::str;
 
@@ -22,7 +21,7 @@ impl TryFrom<i32> for PacketType {
             x if x == Self::Login as i32 => Ok(Self::Login),
             x if x == Self::Command as i32 => Ok(Self::Command),
             x if x == Self::MultiPacketResponse as i32 => Ok(Self::MultiPacketResponse),
-            _ => Err("Unknown value".into()),
+            other => Err(Error::UnknownPacket(other)),
         }
     }
 }
@@ -36,8 +35,8 @@ pub struct Packet {
 
 impl Packet {
     pub fn new(request_id: i32, packet_type: PacketType, payload: String) -> Result<Self, Error> {
-        if payload.chars().any(|c| !c.is_ascii()) {
-            return Err("payload contains a non-ascii character".into());
+        if !payload.is_ascii() {
+            return Err(Error::framing("payload contains a non-ascii character"));
         }
         Ok(Packet {
             request_id,
@@ -101,7 +100,7 @@ pub async fn read<S: AsyncReadExt + Unpin>(source: &mut S) -> Result<Packet, Err
     source.read_exact(&mut raw_packet).await?;
     let packet_bytes = &raw_packet[..];
     trace!("parsing");
-    Packet::parse(&packet_bytes)
+    Packet::parse(packet_bytes)
 }
 
 pub async fn write<W: AsyncWriteExt + Unpin>(packet: &Packet, dest: &mut W) -> Result<(), Error> {
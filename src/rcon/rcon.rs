@@ -47,7 +47,12 @@ impl<S: AsyncReadExt + AsyncWriteExt + Unpin> Connection<S> {
                     ..
                 } if id == cmd.request_id => responses.push(payload),
                 Packet { request_id: id, .. } if id == followup.request_id => break,
-                _ => return Err("Bad packet id received".into()),
+                Packet { request_id: id, .. } => {
+                    return Err(Error::protocol(format!(
+                        "Unexpected rcon request id {}",
+                        id
+                    )))
+                }
             }
         }
         Ok(responses.join(""))
@@ -62,14 +67,17 @@ impl<S: AsyncReadExt + AsyncWriteExt + Unpin> Connection<S> {
         let response = self.receive_packet().await?;
         match response.request_id {
             id if id == request_id => Ok(()),
-            -1 => Err("Invalid RCon password".into()),
-            other_id => Err(format!("Unknown response packet with id {}", other_id).into()),
+            -1 => Err(Error::Auth("Invalid RCon password".to_owned())),
+            other_id => Err(Error::protocol(format!(
+                "Unexpected rcon login response id {}",
+                other_id
+            ))),
         }
     }
 
     async fn send_packet(&mut self, packet: &Packet) -> Result<(), Error> {
         trace!("Sending packet id {}", packet.request_id);
-        Ok(write(packet, &mut self.stream).await?)
+        write(packet, &mut self.stream).await
     }
 
     async fn receive_packet(&mut self) -> Result<Packet, Error> {
@@ -114,7 +122,7 @@ mod tests {
         write(&login_response, &mut conn.stream.input).await?;
         conn.stream.input.set_position(0);
         let login_result = conn.login("password".into()).await;
-        assert!(login_result.is_err()); // TODO: This could be a more general error but error handling is bad right now
+        assert!(matches!(login_result, Err(Error::Auth(_))));
         Ok(())
     }
 
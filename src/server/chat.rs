@@ -0,0 +1,298 @@
+/*
+    Chat components, the json text format used for anything the client shows to players
+    (server list descriptions, disconnect reasons...). Only the parts the facade needs to send are
+    here: plain and translated text, colors, the usual formatting and child components.
+*/
+
+use serde::{Serialize, Serializer};
+use std::mem;
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct Component {
+    #[serde(flatten)]
+    pub content: Content,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub color: Option<Color>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bold: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub italic: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub underlined: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strikethrough: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub obfuscated: Option<bool>,
+    /// Shown after this one, inheriting its formatting
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub extra: Vec<Component>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum Content {
+    Text {
+        text: String,
+    },
+    /// Looked up in the client's language file, with `with` filling in the %s placeholders
+    // Everything the facade says is configured text, so only tests translate
+    #[allow(dead_code)]
+    Translate {
+        translate: String,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        with: Vec<Component>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Color {
+    Black,
+    DarkBlue,
+    DarkGreen,
+    DarkAqua,
+    DarkRed,
+    DarkPurple,
+    Gold,
+    Gray,
+    DarkGray,
+    Blue,
+    Green,
+    Aqua,
+    Red,
+    LightPurple,
+    Yellow,
+    White,
+    /// 0xRRGGBB, only understood by 1.16+ clients. Legacy codes can't make these.
+    #[allow(dead_code)]
+    Rgb(u32),
+}
+
+impl Color {
+    /// The color for a legacy `§` code like the `c` in `§c`
+    pub fn from_code(code: char) -> Option<Color> {
+        use Color::*;
+        Some(match code.to_ascii_lowercase() {
+            '0' => Black,
+            '1' => DarkBlue,
+            '2' => DarkGreen,
+            '3' => DarkAqua,
+            '4' => DarkRed,
+            '5' => DarkPurple,
+            '6' => Gold,
+            '7' => Gray,
+            '8' => DarkGray,
+            '9' => Blue,
+            'a' => Green,
+            'b' => Aqua,
+            'c' => Red,
+            'd' => LightPurple,
+            'e' => Yellow,
+            'f' => White,
+            _ => return None,
+        })
+    }
+
+    fn name(self) -> String {
+        use Color::*;
+        let name = match self {
+            Black => "black",
+            DarkBlue => "dark_blue",
+            DarkGreen => "dark_green",
+            DarkAqua => "dark_aqua",
+            DarkRed => "dark_red",
+            DarkPurple => "dark_purple",
+            Gold => "gold",
+            Gray => "gray",
+            DarkGray => "dark_gray",
+            Blue => "blue",
+            Green => "green",
+            Aqua => "aqua",
+            Red => "red",
+            LightPurple => "light_purple",
+            Yellow => "yellow",
+            White => "white",
+            Rgb(rgb) => return format!("#{:06X}", rgb & 0xFF_FFFF),
+        };
+        name.to_owned()
+    }
+}
+
+impl Serialize for Color {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.name())
+    }
+}
+
+impl Component {
+    pub fn text(text: impl Into<String>) -> Self {
+        Self::with_content(Content::Text { text: text.into() })
+    }
+
+    // The facade makes its components from legacy text, so only tests build them by hand
+    #[allow(dead_code)]
+    pub fn translate(key: impl Into<String>, with: Vec<Component>) -> Self {
+        Self::with_content(Content::Translate {
+            translate: key.into(),
+            with,
+        })
+    }
+
+    fn with_content(content: Content) -> Self {
+        Component {
+            content,
+            color: None,
+            bold: None,
+            italic: None,
+            underlined: None,
+            strikethrough: None,
+            obfuscated: None,
+            extra: vec![],
+        }
+    }
+
+    pub fn color(mut self, color: Color) -> Self {
+        self.color = Some(color);
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn bold(mut self) -> Self {
+        self.bold = Some(true);
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn italic(mut self) -> Self {
+        self.italic = Some(true);
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn extra(mut self, child: Component) -> Self {
+        self.extra.push(child);
+        self
+    }
+
+    /// Turn text with `§` formatting codes (what server.properties and most config files use)
+    /// into components. Like the vanilla client, a color code also resets the formatting.
+    pub fn from_legacy(legacy: &str) -> Self {
+        let mut parts = vec![];
+        let mut style = Component::text("");
+        let mut text = String::new();
+        let mut chars = legacy.chars();
+        while let Some(c) = chars.next() {
+            if c != '§' {
+                text.push(c);
+                continue;
+            }
+            let code = match chars.next() {
+                Some(code) => code.to_ascii_lowercase(),
+                None => break,
+            };
+            if !text.is_empty() {
+                parts.push(style.clone().with_text(mem::take(&mut text)));
+            }
+            match code {
+                'l' => style.bold = Some(true),
+                'o' => style.italic = Some(true),
+                'n' => style.underlined = Some(true),
+                'm' => style.strikethrough = Some(true),
+                'k' => style.obfuscated = Some(true),
+                'r' => style = Component::text(""),
+                _ => {
+                    if let Some(color) = Color::from_code(code) {
+                        style = Component::text("").color(color);
+                    }
+                }
+            }
+        }
+        if !text.is_empty() {
+            parts.push(style.with_text(text));
+        }
+        match parts.len() {
+            0 => Component::text(""),
+            1 => parts.remove(0),
+            // Siblings don't inherit from each other, so an empty parent keeps every part's
+            // formatting separate
+            _ => Component {
+                extra: parts,
+                ..Component::text("")
+            },
+        }
+    }
+
+    fn with_text(mut self, text: String) -> Self {
+        self.content = Content::Text { text };
+        self
+    }
+
+    pub fn to_json(&self) -> String {
+        // Everything in a component is a string, bool or another component, which can't fail
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escaping() {
+        assert_eq!(
+            r#"{"text":"a \"quoted\" \\ backslash\nand a newline"}"#,
+            Component::text("a \"quoted\" \\ backslash\nand a newline").to_json()
+        );
+    }
+
+    #[test]
+    fn test_formatting() {
+        let component = Component::text("Hello").color(Color::Gold).bold().extra(
+            Component::text(" world")
+                .italic()
+                .color(Color::Rgb(0x12ab34)),
+        );
+        assert_eq!(
+            r##"{"text":"Hello","color":"gold","bold":true,"extra":[{"text":" world","color":"#12AB34","italic":true}]}"##,
+            component.to_json()
+        );
+    }
+
+    #[test]
+    fn test_translate() {
+        let component = Component::translate(
+            "multiplayer.disconnect.kicked_by",
+            vec![Component::text("Steve")],
+        );
+        assert_eq!(
+            r#"{"translate":"multiplayer.disconnect.kicked_by","with":[{"text":"Steve"}]}"#,
+            component.to_json()
+        );
+        assert_eq!(
+            r#"{"translate":"multiplayer.disconnect.server_full"}"#,
+            Component::translate("multiplayer.disconnect.server_full", vec![]).to_json()
+        );
+    }
+
+    #[test]
+    fn test_from_legacy() {
+        assert_eq!(Component::text("plain"), Component::from_legacy("plain"));
+        assert_eq!(
+            Component::text("red").color(Color::Red),
+            Component::from_legacy("§cred")
+        );
+        assert_eq!(
+            Component {
+                extra: vec![
+                    Component::text("Fake!\n"),
+                    Component::text("Bold ").color(Color::Gray).bold(),
+                    Component::text("yellow").color(Color::Yellow),
+                    Component::text(" plain"),
+                ],
+                ..Component::text("")
+            },
+            Component::from_legacy("Fake!\n§7§lBold §Eyellow§r plain")
+        );
+        // Dangling and unknown codes just disappear
+        assert_eq!(Component::text("odd"), Component::from_legacy("§zodd§"));
+    }
+}
@@ -0,0 +1,142 @@
+use crate::error::Error;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+use super::packet::{Handshake, PingRequest, PongResponse, StatusRequest, StatusResponse};
+use super::read::packet::read_packet;
+use super::write::packet::write;
+
+// Any reasonably recent protocol version works, servers answer status pings from any version
+const PING_PROTOCOL_VERSION: i32 = 754;
+/// How long a ping gets, so a server that takes connections but never answers can't hold
+/// anything up
+pub const PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub struct PingResult {
+    /// The status json exactly as the server sent it
+    pub status: String,
+    pub latency: Duration,
+}
+
+/// Do the same handshake -> status -> ping dance the Minecraft server list does
+pub async fn ping(addr: &str) -> Result<PingResult, Error> {
+    ping_within(addr, PING_TIMEOUT).await
+}
+
+/// Ping, giving up if it takes longer than `deadline`
+pub async fn ping_within(addr: &str, deadline: Duration) -> Result<PingResult, Error> {
+    match timeout(deadline, exchange(addr)).await {
+        Ok(result) => result,
+        Err(_) => Err(Error::Timeout(format!(
+            "waiting for {} to answer a ping",
+            addr
+        ))),
+    }
+}
+
+async fn exchange(addr: &str) -> Result<PingResult, Error> {
+    let mut socket = TcpStream::connect(addr).await?;
+    let peer = socket.peer_addr()?;
+    write(
+        &Handshake {
+            protocol_version: PING_PROTOCOL_VERSION,
+            server_address: peer.ip().to_string(),
+            server_port: peer.port(),
+            next_state: 1,
+        },
+        &mut socket,
+    )
+    .await?;
+    write(&StatusRequest {}, &mut socket).await?;
+    let status: StatusResponse = read_packet(&mut socket).await?;
+
+    let sent_at = Instant::now();
+    let payload = 1;
+    write(&PingRequest { payload }, &mut socket).await?;
+    let pong: PongResponse = read_packet(&mut socket).await?;
+    if pong.payload != payload {
+        return Err(Error::protocol("Pong payload didn't match our ping"));
+    }
+    Ok(PingResult {
+        status: status.json,
+        latency: sent_at.elapsed(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+    use crate::server::fake_server::{handle_connection, Listing};
+    use crate::server::limits::Limiter;
+    use tokio::net::TcpListener;
+    use tokio::time::sleep;
+
+    #[tokio::test]
+    async fn test_ping_fake_server() -> Result<(), Error> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            let (socket, peer) = listener.accept().await.unwrap();
+            let config = ServerConfig::default();
+            let listing = Listing {
+                description: &config.motd,
+                ..Listing::default()
+            };
+            handle_connection(socket, peer, &config, &listing, &Limiter::default())
+                .await
+                .unwrap();
+        });
+        let result = ping(&addr.to_string()).await?;
+        assert!(result.status.contains("Fake!"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_status_only_once() -> Result<(), Error> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let server = tokio::spawn(async move {
+            let (socket, peer) = listener.accept().await.unwrap();
+            let config = ServerConfig::default();
+            handle_connection(
+                socket,
+                peer,
+                &config,
+                &Listing::default(),
+                &Limiter::default(),
+            )
+            .await
+            .map(|_| ())
+        });
+        let mut socket = TcpStream::connect(addr).await?;
+        let handshake = Handshake {
+            protocol_version: PING_PROTOCOL_VERSION,
+            server_address: "localhost".to_owned(),
+            server_port: addr.port(),
+            next_state: 1,
+        };
+        write(&handshake, &mut socket).await?;
+        write(&StatusRequest {}, &mut socket).await?;
+        let _: StatusResponse = read_packet(&mut socket).await?;
+        write(&StatusRequest {}, &mut socket).await?;
+        assert!(matches!(server.await.unwrap(), Err(Error::Protocol(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ping_timeout() -> Result<(), Error> {
+        // Takes the connection, then says nothing
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            let _socket = listener.accept().await.unwrap();
+            sleep(Duration::from_secs(10)).await;
+        });
+        let result = ping_within(&addr.to_string(), Duration::from_millis(100)).await;
+        assert!(matches!(result, Err(Error::Timeout(_))));
+        Ok(())
+    }
+}
@@ -0,0 +1,532 @@
+/*
+    Encode and Decode are the two halves of the wire format, implemented for every atom (see
+    read::atom and write::atom) and, through the packet! macro, for every packet. Anything that
+    can be written can be read back, so the facade can play either side of a conversation.
+*/
+
+use super::{read::atom as read_atom, write::atom as write_atom};
+use crate::error::Error;
+use std::convert::TryInto;
+use std::fmt;
+use std::io::{Read, Write};
+use std::str::FromStr;
+
+pub trait Encode {
+    fn encode(&self, sink: &mut impl Write) -> Result<(), Error>;
+}
+
+pub trait Decode: Sized {
+    fn decode(source: &mut impl Read) -> Result<Self, Error>;
+}
+
+/// A packet's body, minus the length and id that frame it
+pub trait Packet: Encode + Decode {
+    const ID: i32;
+}
+
+/// An i32 sent as a VarInt instead of four bytes
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct VarInt(pub i32);
+
+impl From<i32> for VarInt {
+    fn from(value: i32) -> Self {
+        VarInt(value)
+    }
+}
+
+impl From<VarInt> for i32 {
+    fn from(value: VarInt) -> Self {
+        value.0
+    }
+}
+
+impl Encode for VarInt {
+    fn encode(&self, sink: &mut impl Write) -> Result<(), Error> {
+        write_atom::write_varint(self.0, sink)
+    }
+}
+
+impl Decode for VarInt {
+    fn decode(source: &mut impl Read) -> Result<Self, Error> {
+        Ok(VarInt(read_atom::read_varint(source)?))
+    }
+}
+
+impl Encode for String {
+    fn encode(&self, sink: &mut impl Write) -> Result<(), Error> {
+        write_atom::write_string(self, sink)
+    }
+}
+
+impl Decode for String {
+    fn decode(source: &mut impl Read) -> Result<Self, Error> {
+        read_atom::read_string(source)
+    }
+}
+
+impl Encode for u16 {
+    fn encode(&self, sink: &mut impl Write) -> Result<(), Error> {
+        write_atom::write_u16(*self, sink)
+    }
+}
+
+impl Decode for u16 {
+    fn decode(source: &mut impl Read) -> Result<Self, Error> {
+        read_atom::read_u16(source)
+    }
+}
+
+impl Encode for i64 {
+    fn encode(&self, sink: &mut impl Write) -> Result<(), Error> {
+        write_atom::write_i64(*self, sink)
+    }
+}
+
+impl Decode for i64 {
+    fn decode(source: &mut impl Read) -> Result<Self, Error> {
+        read_atom::read_i64(source)
+    }
+}
+
+/// An i64 sent as a VarLong
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct VarLong(pub i64);
+
+impl From<i64> for VarLong {
+    fn from(value: i64) -> Self {
+        VarLong(value)
+    }
+}
+
+impl From<VarLong> for i64 {
+    fn from(value: VarLong) -> Self {
+        value.0
+    }
+}
+
+impl Encode for VarLong {
+    fn encode(&self, sink: &mut impl Write) -> Result<(), Error> {
+        write_atom::write_varlong(self.0, sink)
+    }
+}
+
+impl Decode for VarLong {
+    fn decode(source: &mut impl Read) -> Result<Self, Error> {
+        Ok(VarLong(read_atom::read_varlong(source)?))
+    }
+}
+
+/// Bytes with a VarInt length in front. A `Vec<u8>` on its own is a prefixed array too, and
+/// looks the same on the wire, but this reads and writes it in one go.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ByteArray(pub Vec<u8>);
+
+impl From<Vec<u8>> for ByteArray {
+    fn from(value: Vec<u8>) -> Self {
+        ByteArray(value)
+    }
+}
+
+impl From<ByteArray> for Vec<u8> {
+    fn from(value: ByteArray) -> Self {
+        value.0
+    }
+}
+
+impl Encode for ByteArray {
+    fn encode(&self, sink: &mut impl Write) -> Result<(), Error> {
+        write_atom::write_byte_array(&self.0, sink)
+    }
+}
+
+impl Decode for ByteArray {
+    fn decode(source: &mut impl Read) -> Result<Self, Error> {
+        Ok(ByteArray(read_atom::read_byte_array(source)?))
+    }
+}
+
+/// A field newer versions add to the end of a packet, which older clients leave off. It has to be
+/// the last field, since it's there if anything is left to read.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Trailing<T>(pub Option<T>);
+
+impl<T> From<Option<T>> for Trailing<T> {
+    fn from(value: Option<T>) -> Self {
+        Trailing(value)
+    }
+}
+
+impl<T> From<Trailing<T>> for Option<T> {
+    fn from(value: Trailing<T>) -> Self {
+        value.0
+    }
+}
+
+impl<T: Encode> Encode for Trailing<T> {
+    fn encode(&self, sink: &mut impl Write) -> Result<(), Error> {
+        match &self.0 {
+            Some(value) => value.encode(sink),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<T: Decode> Decode for Trailing<T> {
+    fn decode(source: &mut impl Read) -> Result<Self, Error> {
+        let mut rest = vec![];
+        source.read_to_end(&mut rest)?;
+        if rest.is_empty() {
+            return Ok(Trailing(None));
+        }
+        Ok(Trailing(Some(T::decode(&mut &rest[..])?)))
+    }
+}
+
+/// Whatever is left of a packet, kept as it is. Like Trailing it has to be the last field.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Rest(pub Vec<u8>);
+
+impl From<Vec<u8>> for Rest {
+    fn from(value: Vec<u8>) -> Self {
+        Rest(value)
+    }
+}
+
+impl From<Rest> for Vec<u8> {
+    fn from(value: Rest) -> Self {
+        value.0
+    }
+}
+
+impl Encode for Rest {
+    fn encode(&self, sink: &mut impl Write) -> Result<(), Error> {
+        Ok(sink.write_all(&self.0)?)
+    }
+}
+
+impl Decode for Rest {
+    fn decode(source: &mut impl Read) -> Result<Self, Error> {
+        let mut rest = vec![];
+        source.read_to_end(&mut rest)?;
+        Ok(Rest(rest))
+    }
+}
+
+// Positions and identifiers are here for completeness, none of the facade's packets have them yet
+/// A block position, packed into a long as 26 bits of x, 26 of z and 12 of y
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Position {
+    pub x: i32,
+    pub y: i32,
+    pub z: i32,
+}
+
+impl Encode for Position {
+    fn encode(&self, sink: &mut impl Write) -> Result<(), Error> {
+        write_atom::write_position(self, sink)
+    }
+}
+
+impl Decode for Position {
+    fn decode(source: &mut impl Read) -> Result<Self, Error> {
+        read_atom::read_position(source)
+    }
+}
+
+/// A namespaced name like `minecraft:stone` or `velocity:player_info`
+#[allow(dead_code)]
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct Identifier {
+    pub namespace: String,
+    pub path: String,
+}
+
+impl FromStr for Identifier {
+    type Err = Error;
+
+    /// Names without a namespace are in `minecraft`
+    fn from_str(identifier: &str) -> Result<Self, Error> {
+        let (namespace, path) = match identifier.find(':') {
+            Some(colon) => (&identifier[..colon], &identifier[colon + 1..]),
+            None => ("minecraft", identifier),
+        };
+        let allowed = |c: char| matches!(c, 'a'..='z' | '0'..='9' | '.' | '-' | '_');
+        if namespace.is_empty() || !namespace.chars().all(allowed) {
+            return Err(Error::framing(format!(
+                "Bad identifier namespace in {}",
+                identifier
+            )));
+        }
+        if path.is_empty() || !path.chars().all(|c| allowed(c) || c == '/') {
+            return Err(Error::framing(format!(
+                "Bad identifier path in {}",
+                identifier
+            )));
+        }
+        Ok(Identifier {
+            namespace: namespace.to_owned(),
+            path: path.to_owned(),
+        })
+    }
+}
+
+impl fmt::Display for Identifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.namespace, self.path)
+    }
+}
+
+impl Encode for Identifier {
+    fn encode(&self, sink: &mut impl Write) -> Result<(), Error> {
+        write_atom::write_identifier(self, sink)
+    }
+}
+
+impl Decode for Identifier {
+    fn decode(source: &mut impl Read) -> Result<Self, Error> {
+        read_atom::read_identifier(source)
+    }
+}
+
+// Fixed size numbers, all big endian. A u128 is a UUID.
+macro_rules! number_codec {
+    ($($ty:ty => $read:ident, $write:ident;)*) => {
+        $(
+            impl Encode for $ty {
+                fn encode(&self, sink: &mut impl Write) -> Result<(), Error> {
+                    write_atom::$write(*self, sink)
+                }
+            }
+
+            impl Decode for $ty {
+                fn decode(source: &mut impl Read) -> Result<Self, Error> {
+                    read_atom::$read(source)
+                }
+            }
+        )*
+    };
+}
+
+number_codec! {
+    bool => read_bool, write_bool;
+    u8 => read_u8, write_u8;
+    i8 => read_i8, write_i8;
+    i16 => read_i16, write_i16;
+    i32 => read_i32, write_i32;
+    f32 => read_f32, write_f32;
+    f64 => read_f64, write_f64;
+    u128 => read_uuid, write_uuid;
+}
+
+/// A prefixed optional: a bool saying whether the value follows
+impl<T: Encode> Encode for Option<T> {
+    fn encode(&self, sink: &mut impl Write) -> Result<(), Error> {
+        match self {
+            Some(value) => {
+                true.encode(sink)?;
+                value.encode(sink)
+            }
+            None => false.encode(sink),
+        }
+    }
+}
+
+impl<T: Decode> Decode for Option<T> {
+    fn decode(source: &mut impl Read) -> Result<Self, Error> {
+        Ok(match bool::decode(source)? {
+            true => Some(T::decode(source)?),
+            false => None,
+        })
+    }
+}
+
+/// A prefixed array: a VarInt count, then that many values
+impl<T: Encode> Encode for Vec<T> {
+    fn encode(&self, sink: &mut impl Write) -> Result<(), Error> {
+        write_atom::write_varint(self.len().try_into()?, sink)?;
+        for value in self {
+            value.encode(sink)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: Decode> Decode for Vec<T> {
+    fn decode(source: &mut impl Read) -> Result<Self, Error> {
+        let length = read_atom::read_length(source)?;
+        // The count comes from the other side, so don't trust it for more than a small head start
+        let mut values = Vec::with_capacity(length.min(256));
+        for _ in 0..length {
+            values.push(T::decode(source)?);
+        }
+        Ok(values)
+    }
+}
+
+/// Define a packet once and get both directions. Fields are encoded in order, as their own type
+/// or `as` another one they convert to and from (like `i32 as VarInt`).
+///
+///     packet! {
+///         /// Doc comments and other attributes are kept
+///         pub struct PingRequest = 0x01 {
+///             pub payload: i64,
+///         }
+///     }
+macro_rules! packet {
+    (
+        $(#[$meta:meta])*
+        pub struct $name:ident = $id:literal {
+            $(
+                $(#[$field_meta:meta])*
+                pub $field:ident: $ty:ty $(as $wire:ty)?
+            ),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        // Not Eq, since fields can be floats (or NBT with floats in it)
+        #[derive(Debug, Clone, PartialEq)]
+        pub struct $name {
+            $(
+                $(#[$field_meta])*
+                pub $field: $ty,
+            )*
+        }
+
+        impl $crate::server::codec::Encode for $name {
+            #[allow(unused_variables)]
+            fn encode(
+                &self,
+                sink: &mut impl std::io::Write,
+            ) -> Result<(), $crate::error::Error> {
+                $( packet!(@encode self.$field, sink $(, $wire)?); )*
+                Ok(())
+            }
+        }
+
+        impl $crate::server::codec::Decode for $name {
+            #[allow(unused_variables)]
+            fn decode(source: &mut impl std::io::Read) -> Result<Self, $crate::error::Error> {
+                Ok($name {
+                    $( $field: packet!(@decode source $(, $wire)?), )*
+                })
+            }
+        }
+
+        impl $crate::server::codec::Packet for $name {
+            const ID: i32 = $id;
+        }
+    };
+
+    (@encode $value:expr, $sink:ident) => {
+        $crate::server::codec::Encode::encode(&$value, $sink)?
+    };
+    (@encode $value:expr, $sink:ident, $wire:ty) => {
+        $crate::server::codec::Encode::encode(&<$wire>::from($value.clone()), $sink)?
+    };
+    (@decode $source:ident) => {
+        $crate::server::codec::Decode::decode($source)?
+    };
+    (@decode $source:ident, $wire:ty) => {
+        <$wire as $crate::server::codec::Decode>::decode($source)?.into()
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    packet! {
+        pub struct Example = 0x42 {
+            pub count: i32 as VarInt,
+            pub name: String,
+            pub port: u16,
+        }
+    }
+
+    #[test]
+    fn test_packet_round_trip() -> Result<(), Error> {
+        let example = Example {
+            count: 300,
+            name: "hi".to_owned(),
+            port: 25565,
+        };
+        let mut buf = vec![];
+        example.encode(&mut buf)?;
+        assert_eq!(vec![0xac, 0x02, 0x02, b'h', b'i', 0x63, 0xdd], buf);
+        assert_eq!(example, Example::decode(&mut &buf[..])?);
+        assert_eq!(0x42, Example::ID);
+        Ok(())
+    }
+
+    fn round_trip<T: Encode + Decode + std::fmt::Debug + PartialEq>(value: T) -> Result<(), Error> {
+        let mut buf = vec![];
+        value.encode(&mut buf)?;
+        let mut source = &buf[..];
+        assert_eq!(value, T::decode(&mut source)?);
+        assert!(source.is_empty(), "{:?} left bytes behind", value);
+        Ok(())
+    }
+
+    #[test]
+    fn test_atom_round_trips() -> Result<(), Error> {
+        round_trip(VarInt(-1))?;
+        round_trip(VarLong(i64::MIN))?;
+        round_trip(true)?;
+        round_trip(false)?;
+        round_trip(0xfe_u8)?;
+        round_trip(-2_i8)?;
+        round_trip(-300_i16)?;
+        round_trip(25565_u16)?;
+        round_trip(i32::MIN)?;
+        round_trip(i64::MAX)?;
+        round_trip(1.5_f32)?;
+        round_trip(-0.1_f64)?;
+        round_trip(0x0123_4567_89ab_cdef_fedc_ba98_7654_3210_u128)?;
+        round_trip("héllo".to_owned())?;
+        round_trip(Position {
+            x: -1,
+            y: 2047,
+            z: 12345,
+        })?;
+        round_trip("velocity:player_info".parse::<Identifier>()?)?;
+        round_trip(ByteArray(vec![1, 2, 3]))?;
+        round_trip(Some("there".to_owned()))?;
+        round_trip(None::<i32>)?;
+        round_trip(vec![VarInt(1), VarInt(300)])?;
+        round_trip(Vec::<String>::new())?;
+        round_trip(Trailing(Some(true)))?;
+        round_trip(Trailing::<bool>(None))?;
+        round_trip(Rest(vec![1, 2, 3]))?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_wire_formats() -> Result<(), Error> {
+        let mut buf = vec![];
+        Some(7_u8).encode(&mut buf)?;
+        None::<u8>.encode(&mut buf)?;
+        vec![5_u8, 6].encode(&mut buf)?;
+        ByteArray(vec![5, 6]).encode(&mut buf)?;
+        assert_eq!(vec![1, 7, 0, 2, 5, 6, 2, 5, 6], buf);
+        // Prefixed u8 arrays and byte arrays are the same thing
+        let mut source = &buf[3..];
+        assert_eq!(
+            ByteArray::decode(&mut source)?.0,
+            Vec::<u8>::decode(&mut source)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_identifier() -> Result<(), Error> {
+        let stone: Identifier = "stone".parse()?;
+        assert_eq!("minecraft", stone.namespace);
+        assert_eq!("minecraft:stone", stone.to_string());
+        assert_eq!("block/stone", "foo:block/stone".parse::<Identifier>()?.path);
+        assert!("Upper:case".parse::<Identifier>().is_err());
+        assert!("no/slash:in_namespace".parse::<Identifier>().is_err());
+        assert!("empty:".parse::<Identifier>().is_err());
+        Ok(())
+    }
+}
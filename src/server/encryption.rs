@@ -0,0 +1,428 @@
+/*
+    Online mode login encryption. After Login Start:
+        server -> client: Encryption Request, with our public key and a random verify token
+        client -> server: Encryption Response, with a shared secret it made up and the verify
+                          token, both encrypted with our public key
+    Everything after that is AES-128/CFB8 in both directions, with the shared secret as both key
+    and IV. The client tells the session server it's joining using a hash of the secret and our
+    key, and we ask the session server whether it did (see session.rs) to know the player really
+    is who they say.
+*/
+
+use super::intercept::Intercepted;
+use super::limits::{within, Limiter};
+use super::packet::{EncryptionRequest, EncryptionResponse, Handshake, LoginDisconnect};
+use super::session::has_joined;
+use super::write::packet::write;
+use crate::allowlist::format_uuid;
+use crate::error::Error;
+use crate::server::chat::Component;
+use aes::Aes128;
+use cfb8::cipher::generic_array::GenericArray;
+use cfb8::cipher::{BlockDecryptMut, BlockEncryptMut, KeyIvInit};
+use pin_project_lite::pin_project;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use rsa::pkcs8::EncodePublicKey;
+use rsa::{Pkcs1v15Encrypt, RsaPrivateKey};
+use sha1::{Digest, Sha1};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+
+// What the vanilla server uses
+const KEY_BITS: usize = 1024;
+const SECRET_LENGTH: usize = 16;
+// The first release whose Encryption Request says whether to authenticate
+const SHOULD_AUTHENTICATE_PROTOCOL: i32 = 766;
+
+/// The keypair we hand out in Encryption Requests. Like the vanilla server we make a new one every
+/// time we start, nothing needs it to stay the same.
+pub struct ServerKey {
+    private: RsaPrivateKey,
+    public_der: Vec<u8>,
+}
+
+impl ServerKey {
+    pub fn generate() -> Result<Self, Error> {
+        let private = RsaPrivateKey::new(&mut OsRng, KEY_BITS)
+            .map_err(|e| Error::internal(format!("Couldn't generate a server key: {}", e)))?;
+        let public_der = private
+            .to_public_key()
+            .to_public_key_der()
+            .map_err(|e| Error::internal(format!("Couldn't encode the server key: {}", e)))?
+            .into_vec();
+        Ok(ServerKey {
+            private,
+            public_der,
+        })
+    }
+
+    /// The public half, as sent to clients
+    pub fn public_der(&self) -> &[u8] {
+        &self.public_der
+    }
+
+    fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        self.private
+            .decrypt(Pkcs1v15Encrypt, data)
+            .map_err(|e| Error::protocol(format!("Couldn't decrypt the login: {}", e)))
+    }
+}
+
+/// Minecraft's odd hex digest, which reads the sha1 as a signed number: "-" and the two's
+/// complement for negative ones, and no leading zeros
+pub fn server_hash(server_id: &str, shared_secret: &[u8], public_der: &[u8]) -> String {
+    let mut digest: [u8; 20] = Sha1::new()
+        .chain_update(server_id.as_bytes())
+        .chain_update(shared_secret)
+        .chain_update(public_der)
+        .finalize()
+        .into();
+    let negative = digest[0] & 0x80 != 0;
+    if negative {
+        // Negate: flip every bit and add one, carrying from the end
+        let mut carry = true;
+        for byte in digest.iter_mut().rev() {
+            let (sum, overflowed) = (!*byte).overflowing_add(carry as u8);
+            *byte = sum;
+            carry = overflowed;
+        }
+    }
+    let hex: String = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+    let hex = hex.trim_start_matches('0');
+    format!("{}{}", if negative { "-" } else { "" }, hex)
+}
+
+pin_project! {
+    /// A stream that's AES/CFB8 encrypted both ways, as a connection is after the Encryption
+    /// Response
+    pub struct EncryptedStream<S> {
+        #[pin]
+        inner: S,
+        encryptor: cfb8::Encryptor<Aes128>,
+        decryptor: cfb8::Decryptor<Aes128>,
+        // Encrypted bytes the inner stream hasn't taken yet. They've already been through the
+        // cipher, so they can't be encrypted again on the next write.
+        pending: Vec<u8>,
+    }
+}
+
+impl<S> EncryptedStream<S> {
+    pub fn new(inner: S, shared_secret: &[u8]) -> Result<Self, Error> {
+        if shared_secret.len() != SECRET_LENGTH {
+            return Err(Error::protocol(format!(
+                "Shared secret should be {} bytes, got {}",
+                SECRET_LENGTH,
+                shared_secret.len()
+            )));
+        }
+        // Can't fail now the length has been checked
+        let encryptor = cfb8::Encryptor::new_from_slices(shared_secret, shared_secret).unwrap();
+        let decryptor = cfb8::Decryptor::new_from_slices(shared_secret, shared_secret).unwrap();
+        Ok(EncryptedStream {
+            inner,
+            encryptor,
+            decryptor,
+            pending: vec![],
+        })
+    }
+}
+
+impl<S: AsyncWrite> EncryptedStream<S> {
+    fn poll_pending(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut this = self.project();
+        while !this.pending.is_empty() {
+            let written = match this.inner.as_mut().poll_write(cx, this.pending) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                Poll::Ready(Ok(written)) => written,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            };
+            this.pending.drain(..written);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for EncryptedStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let already_filled = buf.filled().len();
+        let result = this.inner.poll_read(cx, buf);
+        for byte in &mut buf.filled_mut()[already_filled..] {
+            this.decryptor
+                .decrypt_block_mut(GenericArray::from_mut_slice(std::slice::from_mut(byte)));
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite> AsyncWrite for EncryptedStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        // Only take more once the last write is out, so pending can't grow without bound
+        match self.as_mut().poll_pending(cx) {
+            Poll::Ready(Ok(())) => (),
+            other => return other.map_ok(|_| 0),
+        }
+        let this = self.as_mut().project();
+        let start = this.pending.len();
+        this.pending.extend_from_slice(buf);
+        for byte in &mut this.pending[start..] {
+            this.encryptor
+                .encrypt_block_mut(GenericArray::from_mut_slice(std::slice::from_mut(byte)));
+        }
+        // The bytes are ours now, whether or not they make it out straight away
+        let _ = self.poll_pending(cx)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.as_mut().poll_pending(cx) {
+            Poll::Ready(Ok(())) => self.project().inner.poll_flush(cx),
+            other => other,
+        }
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.as_mut().poll_pending(cx) {
+            Poll::Ready(Ok(())) => self.project().inner.poll_shutdown(cx),
+            other => other,
+        }
+    }
+}
+
+/// A login that's been through the encryption handshake
+pub struct EncryptedLogin {
+    pub handshake: Handshake,
+    /// What the client said until verify, then what the session server says
+    pub name: String,
+    pub uuid: Option<u128>,
+    pub peer: SocketAddr,
+    server_hash: String,
+    stream: EncryptedStream<TcpStream>,
+}
+
+impl EncryptedLogin {
+    /// Make sure they really are who they said, with the session server
+    pub async fn verify(&mut self) -> Result<(), Error> {
+        let profile = has_joined(&self.name, &self.server_hash).await?;
+        let uuid_matches = self.uuid.is_none_or(|uuid| uuid == profile.uuid);
+        if profile.name != self.name || !uuid_matches {
+            return Err(Error::protocol(format!(
+                "{} logged in, but the session server says they're {} ({})",
+                self.name,
+                profile.name,
+                format_uuid(profile.uuid)
+            )));
+        }
+        self.uuid = Some(profile.uuid);
+        Ok(())
+    }
+
+    /// Turn them away. The real server can't take over an encrypted connection, so this is
+    /// the only thing left to do with one.
+    pub async fn kick(mut self, reason: &Component) -> Result<(), Error> {
+        write(&LoginDisconnect::new(reason), &mut self.stream).await
+    }
+}
+
+/// Run the encryption handshake with someone who has just sent Login Start, holding their
+/// response to the same limits as everything else they send
+pub async fn encrypt_login(
+    login: Intercepted,
+    key: &ServerKey,
+    limiter: &Limiter,
+) -> Result<EncryptedLogin, Error> {
+    let name = login.player().unwrap_or_default().to_owned();
+    let uuid = login.uuid();
+    let peer = login.peer;
+    let handshake = login.handshake.clone();
+    let (mut socket, _) = login.into_parts();
+    let protocol = handshake.protocol_version;
+    let shared_secret = exchange_secret(&mut socket, key, protocol, limiter).await?;
+    Ok(EncryptedLogin {
+        handshake,
+        name,
+        uuid,
+        peer,
+        server_hash: server_hash("", &shared_secret, key.public_der()),
+        stream: EncryptedStream::new(socket, &shared_secret)?,
+    })
+}
+
+// Send an Encryption Request and check the response, returning the shared secret
+async fn exchange_secret<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: &mut S,
+    key: &ServerKey,
+    protocol: i32,
+    limiter: &Limiter,
+) -> Result<Vec<u8>, Error> {
+    let mut verify_token = vec![0; 4];
+    OsRng.fill_bytes(&mut verify_token);
+    let request = EncryptionRequest {
+        server_id: "".to_owned(),
+        public_key: key.public_der().to_vec(),
+        verify_token: verify_token.clone(),
+        should_authenticate: Some(true).filter(|_| protocol >= SHOULD_AUTHENTICATE_PROTOCOL),
+    };
+    write(&request, socket).await?;
+    let framing = limiter.framing();
+    let read = framing.read_packet(socket);
+    let response: EncryptionResponse =
+        within(limiter.read_timeout(), "an encryption response", read).await?;
+    if key.decrypt(&response.verify_token)? != verify_token {
+        return Err(Error::protocol("Verify token didn't match"));
+    }
+    key.decrypt(&response.shared_secret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LimitsConfig;
+    use crate::server::read::packet::read_packet;
+    use rsa::pkcs8::DecodePublicKey;
+    use rsa::RsaPublicKey;
+    use std::sync::OnceLock;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    // Key generation is slow in debug builds, so every test shares one
+    fn key() -> &'static ServerKey {
+        static KEY: OnceLock<ServerKey> = OnceLock::new();
+        KEY.get_or_init(|| ServerKey::generate().unwrap())
+    }
+
+    #[test]
+    fn test_server_hash() {
+        // The examples everyone uses, from wiki.vg
+        assert_eq!(
+            "4ed1f46bbe04bc756bcb17c0c7ce3e4632f06a48",
+            server_hash("Notch", &[], &[])
+        );
+        assert_eq!(
+            "-7c9d5b0044c130109a5d7b5fb5c317c02b4e28c1",
+            server_hash("jeb_", &[], &[])
+        );
+        assert_eq!(
+            "88e16a1019277b15d58faf0541e11910eb756f6",
+            server_hash("simon", &[], &[])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_stream() -> Result<(), Error> {
+        let secret = [7; SECRET_LENGTH];
+        let (client, server) = duplex(64);
+        let mut client = EncryptedStream::new(client, &secret)?;
+        let mut server = EncryptedStream::new(server, &secret)?;
+        // More than the pipe holds, so some of it has to wait in pending
+        let message = vec![42; 1000];
+        let expected = message.clone();
+        let writer = tokio::spawn(async move {
+            client.write_all(&message).await.unwrap();
+            client.flush().await.unwrap();
+        });
+        let mut received = vec![0; 1000];
+        server.read_exact(&mut received).await?;
+        assert_eq!(expected, received);
+
+        writer.await.unwrap();
+
+        // What actually goes over the wire is scrambled
+        let (client, mut raw_server) = duplex(64);
+        let mut client = EncryptedStream::new(client, &secret)?;
+        client.write_all(&[42; 4]).await?;
+        client.flush().await?;
+        let mut scrambled = [0; 4];
+        raw_server.read_exact(&mut scrambled).await?;
+        assert_ne!([42; 4], scrambled);
+        Ok(())
+    }
+
+    #[test]
+    fn test_wrong_secret_length() {
+        assert!(EncryptedStream::new(vec![0u8; 0], &[0; 15]).is_err());
+    }
+
+    // Play the client's side of the handshake
+    async fn client_side<S: AsyncRead + AsyncWrite + Unpin>(
+        socket: &mut S,
+        secret: &[u8],
+        tamper: bool,
+    ) -> Result<EncryptionRequest, Error> {
+        let request: EncryptionRequest = read_packet(socket).await?;
+        let public = RsaPublicKey::from_public_key_der(&request.public_key).unwrap();
+        let mut token = request.verify_token.clone();
+        if tamper {
+            token[0] ^= 1;
+        }
+        let response = EncryptionResponse {
+            shared_secret: public.encrypt(&mut OsRng, Pkcs1v15Encrypt, secret).unwrap(),
+            verify_token: public.encrypt(&mut OsRng, Pkcs1v15Encrypt, &token).unwrap(),
+        };
+        write(&response, socket).await?;
+        Ok(request)
+    }
+
+    #[tokio::test]
+    async fn test_exchange_secret() -> Result<(), Error> {
+        let secret = [3; SECRET_LENGTH];
+        let (mut client, mut server) = duplex(1024);
+        let client = tokio::spawn(async move {
+            let request = client_side(&mut client, &secret, false).await.unwrap();
+            assert_eq!(Some(true), request.should_authenticate);
+        });
+        assert_eq!(
+            secret.to_vec(),
+            exchange_secret(&mut server, key(), 766, &Limiter::default()).await?
+        );
+        client.await.unwrap();
+
+        let (mut client, mut server) = duplex(1024);
+        let client = tokio::spawn(async move {
+            let request = client_side(&mut client, &secret, true).await.unwrap();
+            assert_eq!(None, request.should_authenticate);
+        });
+        assert!(matches!(
+            exchange_secret(&mut server, key(), 754, &Limiter::default()).await,
+            Err(Error::Protocol(_))
+        ));
+        client.await.unwrap();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_exchange_limits() -> Result<(), Error> {
+        let limiter = Limiter::new(LimitsConfig {
+            read_timeout_secs: 1,
+            ..LimitsConfig::default()
+        });
+        // Never answers
+        let (_client, mut server) = duplex(1024);
+        assert!(matches!(
+            exchange_secret(&mut server, key(), 766, &limiter).await,
+            Err(Error::Timeout(_))
+        ));
+
+        // Says its response is 2MB
+        let (mut client, mut server) = duplex(1024);
+        client.write_all(&[0xff, 0xff, 0x7f]).await?;
+        assert!(matches!(
+            exchange_secret(&mut server, key(), 766, &limiter).await,
+            Err(Error::Framing(_))
+        ));
+        Ok(())
+    }
+}
@@ -1,67 +1,265 @@
+use crate::allowlist::format_uuid;
+use crate::config::{Config, ServerConfig};
 use crate::error::Error;
+use crate::proxy_protocol;
+use crate::wake::{self, Decision, WakePolicy, WakeRequest};
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::broadcast;
 
-use super::read::packet::*;
+use super::chat::Component;
+use super::encryption::EncryptedLogin;
+use super::favicon::{Favicon, Favicons};
+use super::intercept::{intercept, Intercepted};
+use super::legacy::{self, is_legacy_ping, LegacyStatus};
+use super::limits::{within, Limiter};
+use super::packet::{LoginDisconnect, PongResponse, StatusResponse};
+use super::read::packet::{ConnectionState, Serverbound};
+use super::status::{ServerStatus, StatusCache};
+use super::version::release_name;
 
-use super::write::packet::{write, HandshakeResponse, LoginDisconnect, Pong};
+use super::write::packet::write;
 use crate::util::race::{race, RaceResult};
 
-enum ConnectionResult {
-    Login,
+pub(crate) enum ConnectionResult {
+    /// Someone is trying to log in. Nothing has been sent back yet, so they can still be kicked
+    /// or handed over to the real server.
+    Login(Intercepted),
     ServerListPing,
+    /// Closed for going over a limit, which has already been counted
+    Limited,
 }
 
-async fn handle_connection(mut socket: TcpStream) -> Result<ConnectionResult, Error> {
+/// What people pinging the fake server get to see
+#[derive(Default)]
+pub(crate) struct Listing<'a> {
+    pub description: &'a str,
+    pub favicon: Option<&'a Favicon>,
+    /// The real server's own status, which everything else gets copied from if it's there
+    pub cache: Option<&'a StatusCache>,
+    /// The protocol the real server speaks, if we know it
+    pub protocol: Option<i32>,
+}
+
+// The configured version name, or the name of the release speaking `protocol`
+fn version_name(config: &ServerConfig, protocol: Option<i32>) -> String {
+    config
+        .version_name
+        .as_deref()
+        .or_else(|| protocol.and_then(release_name))
+        .unwrap_or("Unknown version")
+        .to_owned()
+}
+
+pub(crate) async fn handle_connection(
+    mut socket: TcpStream,
+    // Where it really came from, which isn't the other end of the socket behind a load balancer
+    peer: SocketAddr,
+    config: &ServerConfig,
+    listing: &Listing<'_>,
+    limiter: &Limiter,
+) -> Result<ConnectionResult, Error> {
     debug!("Starting to handle a connection");
-    if let Packet::Handshake(handshake) = read(&mut socket).await? {
-        // first a handshake
-        debug!("Got a handshake packet");
-        if handshake.next_state == 2 {
-            // login request
-            debug!("packet is a login packet");
-            write(
-                &LoginDisconnect {
-                    reason: "Starting the real server, this could take a bit",
-                },
-                &mut socket,
-            )
-            .await?;
-            return Ok(ConnectionResult::Login);
+    let ip = peer.ip();
+    // One deadline for all of it, so it can't be dragged out a byte at a time
+    let handshake_timeout = limiter.handshake_timeout();
+    let started = Instant::now();
+    let legacy = within(handshake_timeout, "a handshake", is_legacy_ping(&socket)).await?;
+    if legacy {
+        if !limiter.allow_ping(ip) {
+            return Ok(ConnectionResult::Limited);
         }
-        debug!("packet is a server list ping packet");
-        // Then a request for a response (no idea why these aren't the same)
-        if let Packet::HandshakeRequest(_handshake_request) = read(&mut socket).await? {
-            write(
-                &HandshakeResponse {
-                    protocol: handshake.protocol_version,
-                    version_name: "test".to_owned(), // Need to fake this based on the request
-                    description: "Fake!".to_owned(),
-                    max_players: 1,
-                    online_players: 0,
-                },
-                &mut socket,
-            )
-            .await?;
-            if let Packet::Ping(ping) = read(&mut socket).await? {
+        let status = LegacyStatus {
+            version_name: &version_name(config, listing.protocol),
+            motd: listing.description,
+            online: 0,
+            max: config.max_players,
+        };
+        let answer = legacy::answer(&mut socket, &status);
+        within(limiter.read_timeout(), "a legacy ping", answer).await?;
+        return Ok(ConnectionResult::ServerListPing);
+    }
+    // first a handshake
+    let left = handshake_timeout.saturating_sub(started.elapsed());
+    let mut intercepted = within(left, "a handshake", intercept(socket, limiter.framing())).await?;
+    intercepted.peer = peer;
+    debug!("Got a handshake packet");
+    if intercepted.is_login() {
+        debug!("packet is a login packet");
+        return Ok(ConnectionResult::Login(intercepted));
+    }
+    if !limiter.allow_ping(ip) {
+        return Ok(ConnectionResult::Limited);
+    }
+    debug!("packet is a server list ping packet");
+    // Clients with a different protocol get shown as incompatible, but if we don't know what the
+    // real server runs it's better to look compatible than to turn people away
+    let protocol = listing
+        .protocol
+        .unwrap_or(intercepted.handshake.protocol_version);
+    let socket = intercepted.socket_mut();
+    let framing = limiter.framing();
+    // The client asks for the status, then usually pings to see how far away we are. Either
+    // one can come first, and the client can hang up at any point. Each only once, or one
+    // client could keep a connection forever by asking for the status over and over.
+    let mut answered = false;
+    loop {
+        let read = framing.read(socket, ConnectionState::Status);
+        let packet = match within(limiter.read_timeout(), "a status request", read).await {
+            Ok(packet) => packet,
+            Err(Error::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        };
+        match packet {
+            Serverbound::StatusRequest(_) if answered => {
+                return Err(Error::protocol("Asked for the status twice"));
+            }
+            Serverbound::StatusRequest(_) => {
+                answered = true;
+                // Look like the real server if we've seen it, otherwise make something up
+                let description = Component::from_legacy(listing.description);
+                let favicon = listing.favicon.map(Favicon::data_uri);
+                let cached = listing
+                    .cache
+                    .and_then(|cache| cache.with_description(&description, favicon));
+                let json = cached.unwrap_or_else(|| {
+                    ServerStatus {
+                        protocol,
+                        version_name: version_name(config, Some(protocol)),
+                        description,
+                        max_players: config.max_players,
+                        online_players: 0,
+                        favicon: favicon.map(str::to_owned),
+                    }
+                    .to_json()
+                });
+                write(&StatusResponse { json }, socket).await?;
+            }
+            Serverbound::PingRequest(ping) => {
                 debug!("Got a ping");
-                write(
-                    &Pong {
-                        payload: ping.payload,
-                    },
-                    &mut socket,
-                )
-                .await?;
+                let payload = ping.payload;
+                write(&PongResponse { payload }, socket).await?;
+                // Nothing comes after the ping
+                break;
+            }
+            other => {
+                return Err(Error::protocol(format!(
+                    "Unexpected {:?} during a status request",
+                    other
+                )))
             }
-            return Ok(ConnectionResult::ServerListPing);
         }
     }
-    Err("Not a handshake packet".into())
+    Ok(ConnectionResult::ServerListPing)
+}
+
+/// Turn away someone who is logging in
+pub(crate) async fn kick(mut login: Intercepted, reason: &Component) -> Result<(), Error> {
+    write(&LoginDisconnect::new(reason), login.socket_mut()).await
+}
+
+/// Turn away anyone whose client can't play on the real server (which runs `backend`, if we
+/// know), then put them to the wake policy if there is one. Anyone turned away gets logged and
+/// kicked, and None comes back.
+pub(crate) async fn admit(
+    policy: Option<&dyn WakePolicy>,
+    login: Intercepted,
+    config: &Config,
+    backend: Option<i32>,
+) -> Option<Intercepted> {
+    let player = login.player().unwrap_or_default();
+    let client = login.handshake.protocol_version;
+    let reason = match incompatible(&config.server, backend, client) {
+        Some(reason) => {
+            info!("Turning away {} (protocol {}): {}", player, client, reason);
+            reason
+        }
+        None => {
+            let request = WakeRequest {
+                handshake: &login.handshake,
+                player,
+                uuid: login.uuid(),
+                peer: login.peer,
+                at: Instant::now(),
+            };
+            match policy.and_then(|policy| refusal(policy, &request, config)) {
+                Some(reason) => reason,
+                None => return Some(login),
+            }
+        }
+    };
+    if let Err(e) = kick(login, &Component::from_legacy(&reason)).await {
+        error!("{}", e);
+    }
+    None
+}
+
+/// admit, for someone who has been through the encryption handshake
+pub(crate) async fn admit_encrypted(
+    policy: &dyn WakePolicy,
+    login: EncryptedLogin,
+    config: &Config,
+) -> Option<EncryptedLogin> {
+    let request = WakeRequest {
+        handshake: &login.handshake,
+        player: &login.name,
+        uuid: login.uuid,
+        peer: login.peer,
+        at: Instant::now(),
+    };
+    let reason = match refusal(policy, &request, config) {
+        Some(reason) => reason,
+        None => return Some(login),
+    };
+    if let Err(e) = login.kick(&Component::from_legacy(&reason)).await {
+        error!("{}", e);
+    }
+    None
+}
+
+/// What to tell a client that can't play on the real server, or None if it can (or we don't
+/// know what the real server runs)
+pub fn incompatible(config: &ServerConfig, backend: Option<i32>, client: i32) -> Option<String> {
+    let backend = backend?;
+    if backend == client {
+        return None;
+    }
+    let release = match release_name(backend) {
+        Some(release) => release.to_owned(),
+        None => format!("protocol {}", backend),
+    };
+    Some(config.incompatible_message.replace("{version}", &release))
+}
+
+// Why the policy won't wake the real server for someone, if it won't
+fn refusal(policy: &dyn WakePolicy, request: &WakeRequest, config: &Config) -> Option<String> {
+    let reason = match policy.decide(request) {
+        Decision::Wake => return None,
+        Decision::Reject(reason) => reason,
+        Decision::Defer => config.wake.deferred_message.clone(),
+    };
+    info!(
+        "Not waking the real server for {} (UUID {}, from {}): {}",
+        request.player,
+        request
+            .uuid
+            .map_or_else(|| "unknown".to_owned(), format_uuid),
+        request.peer,
+        reason
+    );
+    Some(reason)
 }
 
 /// Run a fake server until someone logs in
-pub async fn run_fake_server(addr: &str) -> Result<(), Error> {
-    let mut listener = TcpListener::bind(&addr).await?;
+pub async fn run_fake_server(config: Arc<Config>) -> Result<(), Error> {
+    let addr = &config.server.listen;
+    let favicons = Arc::new(Favicons::load(&config.server)?);
+    let policy = wake::from_config(&config)?;
+    let limiter = Arc::new(Limiter::new(config.limits.clone()));
+    let listener = TcpListener::bind(addr).await?;
     let (tx, rx1) = broadcast::channel::<()>(1); // Only ever going to be 1 value
                                                  // it would probably be better to use this rather than spawn new ones, but that's more complex.
                                                  // we also want to drop it so that the sender doesn't have to give it messages ever
@@ -71,18 +269,70 @@ pub async fn run_fake_server(addr: &str) -> Result<(), Error> {
         match race(listener.accept(), tx.subscribe().recv()).await {
             RaceResult::Left(listener_result) => {
                 debug!("Got a socket connection");
-                let (socket, _) = listener_result?;
+                let (mut socket, peer) = listener_result?;
+                let accept_header = config.proxy_protocol.accept;
+                if accept_header && !config.proxy_protocol.trusts(peer.ip()) {
+                    debug!(
+                        "Closed a connection from {}, which isn't a trusted balancer",
+                        peer
+                    );
+                    continue;
+                }
+                // A balancer's connections only count towards the cap until their header is read
+                let permit = match accept_header {
+                    true => limiter.reserve(peer.ip()),
+                    false => limiter.admit(peer.ip()),
+                };
+                // Dropping the socket closes it
+                let permit = match permit {
+                    Some(permit) => permit,
+                    None => continue,
+                };
+                let limiter = limiter.clone();
                 let tx = tx.clone();
+                let config = config.clone();
+                let favicons = favicons.clone();
+                let policy = policy.clone();
                 tokio::spawn(async move {
-                    match handle_connection(socket).await {
-                        Ok(ConnectionResult::Login) => {
+                    let (peer, _permit) = match accept_header {
+                        true => match proxy_protocol::accept(&mut socket).await {
+                            Ok(peer) => match limiter.rate_limit(permit, peer.ip()) {
+                                Some(permit) => (peer, permit),
+                                None => return,
+                            },
+                            Err(e) => {
+                                return debug!("Closed a connection without a PROXY header: {}", e)
+                            }
+                        },
+                        false => (peer, permit),
+                    };
+                    // Nothing ever wakes up here, so it's always asleep
+                    let listing = Listing {
+                        description: &config.server.motd,
+                        favicon: favicons.pick(true),
+                        cache: None,
+                        protocol: None,
+                    };
+                    match handle_connection(socket, peer, &config.server, &listing, &limiter).await
+                    {
+                        Ok(ConnectionResult::Login(login)) => {
+                            let backend = config.backend.protocol();
+                            let login = match admit(Some(&*policy), login, &config, backend).await {
+                                Some(login) => login,
+                                None => return,
+                            };
+                            let reason = Component::from_legacy(&config.server.kick_message);
+                            if let Err(e) = kick(login, &reason).await {
+                                error!("{}", e);
+                            }
                             info!("Finished a login");
                             tx.send(()).unwrap();
                         }
                         Ok(ConnectionResult::ServerListPing) => {
                             info!("Finished a server list ping")
                         }
-                        Err(e) => error!("{}", e),
+                        Ok(ConnectionResult::Limited) => (),
+                        Err(e) => limiter.failed(&e),
                     }
                 });
             }
@@ -95,3 +345,52 @@ pub async fn run_fake_server(addr: &str) -> Result<(), Error> {
     info!("Shutting down server");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::packet::Handshake;
+    use crate::server::read::packet::read_packet;
+    use tokio::io::AsyncWriteExt;
+    use tokio::time::{sleep, Duration};
+
+    #[tokio::test]
+    async fn test_serve_turns_away_incompatible() -> Result<(), Error> {
+        let addr = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+        let mut config = Config::default();
+        config.server.listen = addr.to_string();
+        config.backend.version = Some("1.20.4".to_owned());
+        tokio::spawn(run_fake_server(Arc::new(config)));
+        sleep(Duration::from_millis(100)).await;
+
+        let mut socket = TcpStream::connect(addr).await?;
+        let handshake = Handshake {
+            protocol_version: 754,
+            server_address: "localhost".to_owned(),
+            server_port: addr.port(),
+            next_state: 2,
+        };
+        write(&handshake, &mut socket).await?;
+        socket
+            .write_all(&[0x05, 0x00, 0x03, b'b', b'o', b'b'])
+            .await?; // login start
+        let disconnect: LoginDisconnect = read_packet(&mut socket).await?;
+        assert!(disconnect.reason.contains("This server runs 1.20.4"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_incompatible() {
+        let config = ServerConfig::default();
+        assert_eq!(None, incompatible(&config, None, 754));
+        assert_eq!(None, incompatible(&config, Some(754), 754));
+        assert_eq!(
+            Some("This server runs 1.20.4".to_owned()),
+            incompatible(&config, Some(765), 754)
+        );
+        assert_eq!(
+            Some("This server runs protocol 9999".to_owned()),
+            incompatible(&config, Some(9999), 754)
+        );
+    }
+}
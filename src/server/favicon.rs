@@ -0,0 +1,123 @@
+use crate::config::ServerConfig;
+use crate::error::Error;
+use std::convert::TryInto;
+use std::fs;
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+// The client won't show anything else
+const SIZE: u32 = 64;
+
+/// A server list icon, ready to go in the status json
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Favicon {
+    data_uri: String,
+}
+
+impl Favicon {
+    pub fn load(path: &str) -> Result<Self, Error> {
+        let png = fs::read(path)
+            .map_err(|e| Error::config(format!("Couldn't read favicon {}: {}", path, e)))?;
+        Self::from_png(&png).map_err(|e| Error::config(format!("Bad favicon {}: {}", path, e)))
+    }
+
+    pub fn from_png(png: &[u8]) -> Result<Self, Error> {
+        let (width, height) = png_size(png)?;
+        if (width, height) != (SIZE, SIZE) {
+            return Err(Error::framing(format!(
+                "must be {}x{} pixels, not {}x{}",
+                SIZE, SIZE, width, height
+            )));
+        }
+        Ok(Favicon {
+            data_uri: format!("data:image/png;base64,{}", base64::encode(png)),
+        })
+    }
+
+    pub fn data_uri(&self) -> &str {
+        &self.data_uri
+    }
+}
+
+// The size is the first thing in the IHDR chunk, which always comes straight after the signature
+fn png_size(png: &[u8]) -> Result<(u32, u32), Error> {
+    if !png.starts_with(PNG_SIGNATURE) {
+        return Err(Error::framing("not a png"));
+    }
+    let ihdr = png
+        .get(PNG_SIGNATURE.len()..PNG_SIGNATURE.len() + 16)
+        .filter(|header| &header[4..8] == b"IHDR")
+        .ok_or_else(|| Error::framing("png is missing its header"))?;
+    let width = u32::from_be_bytes(ihdr[8..12].try_into()?);
+    let height = u32::from_be_bytes(ihdr[12..16].try_into()?);
+    Ok((width, height))
+}
+
+/// The icons from server.favicon and server.sleeping_favicon
+#[derive(Debug, Default)]
+pub struct Favicons {
+    pub awake: Option<Favicon>,
+    pub sleeping: Option<Favicon>,
+}
+
+impl Favicons {
+    pub fn load(config: &ServerConfig) -> Result<Self, Error> {
+        let load = |path: &Option<String>| path.as_deref().map(Favicon::load).transpose();
+        Ok(Favicons {
+            awake: load(&config.favicon)?,
+            sleeping: load(&config.sleeping_favicon)?,
+        })
+    }
+
+    /// The icon to show, falling back to the normal one if there's no sleeping one
+    pub fn pick(&self, sleeping: bool) -> Option<&Favicon> {
+        match &self.sleeping {
+            Some(icon) if sleeping => Some(icon),
+            _ => self.awake.as_ref(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Just enough of a png to get past the checks: the signature and an IHDR chunk
+    fn fake_png(width: u32, height: u32) -> Vec<u8> {
+        let mut png = PNG_SIGNATURE.to_vec();
+        png.extend_from_slice(&13u32.to_be_bytes());
+        png.extend_from_slice(b"IHDR");
+        png.extend_from_slice(&width.to_be_bytes());
+        png.extend_from_slice(&height.to_be_bytes());
+        png.extend_from_slice(&[8, 6, 0, 0, 0]);
+        png
+    }
+
+    #[test]
+    fn test_from_png() -> Result<(), Error> {
+        let icon = Favicon::from_png(&fake_png(64, 64))?;
+        assert!(icon
+            .data_uri()
+            .starts_with("data:image/png;base64,iVBORw0KGgo"));
+        assert!(Favicon::from_png(&fake_png(128, 128)).is_err());
+        assert!(Favicon::from_png(b"GIF89a").is_err());
+        assert!(Favicon::from_png(PNG_SIGNATURE).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_pick() -> Result<(), Error> {
+        let awake = Favicon::from_png(&fake_png(64, 64))?;
+        let mut favicons = Favicons {
+            awake: Some(awake.clone()),
+            sleeping: None,
+        };
+        assert_eq!(Some(&awake), favicons.pick(true));
+        let sleeping = Favicon {
+            data_uri: "data:image/png;base64,zzz".to_owned(),
+        };
+        favicons.sleeping = Some(sleeping.clone());
+        assert_eq!(Some(&sleeping), favicons.pick(true));
+        assert_eq!(Some(&awake), favicons.pick(false));
+        Ok(())
+    }
+}
@@ -0,0 +1,300 @@
+/*
+    How packets are wrapped on the wire. Every connection starts out uncompressed:
+        VarInt length, VarInt packet id, body
+    Once the server sends Set Compression (during login), both sides switch to:
+        VarInt length, VarInt data length, then either
+            the id and body as they are, with a data length of 0, if they're under the threshold
+            the id and body zlib compressed, with their uncompressed size as the data length
+    The switch happens right after Set Compression, so the same connection needs both.
+*/
+
+use super::codec::Packet;
+use super::read::atom as read_atom;
+use super::read::packet::{decode, ConnectionState, Serverbound};
+use super::write::atom as write_atom;
+use crate::error::Error;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use std::convert::TryInto;
+use std::io::{Cursor, Read, Write};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+// The most the vanilla server will decompress a packet to
+const MAX_DATA_LENGTH: usize = 8 * 1024 * 1024;
+// The longest frame there can be, since vanilla only reads 3 bytes of length
+pub const MAX_FRAME_LENGTH: usize = (1 << 21) - 1;
+
+/// Which frame format a connection is using. Keep one per connection (per direction, for a
+/// proxy) and switch it over when Set Compression goes past.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Framing {
+    threshold: Option<usize>,
+    // Frames are read into memory whole, so this is how much one can cost us
+    max_length: usize,
+}
+
+impl Default for Framing {
+    fn default() -> Self {
+        Framing {
+            threshold: None,
+            max_length: MAX_FRAME_LENGTH,
+        }
+    }
+}
+
+impl Framing {
+    /// Compress packets at least `threshold` bytes long. Negative thresholds turn compression
+    /// off, the same as in Set Compression.
+    // The facade hands connections over before compression starts, so only tests switch
+    #[allow(dead_code)]
+    pub fn compressed(threshold: i32) -> Self {
+        let mut framing = Framing::default();
+        framing.set_compression(threshold);
+        framing
+    }
+
+    /// Refuse to read frames longer than `max_length`, for when the other side isn't trusted
+    /// with the full 2MiB
+    pub fn with_max_length(mut self, max_length: usize) -> Self {
+        self.max_length = max_length.min(MAX_FRAME_LENGTH);
+        self
+    }
+
+    /// Switch formats, for when Set Compression is sent or received
+    #[allow(dead_code)]
+    pub fn set_compression(&mut self, threshold: i32) {
+        self.threshold = threshold.try_into().ok();
+    }
+
+    #[allow(dead_code)]
+    pub fn threshold(&self) -> Option<usize> {
+        self.threshold
+    }
+
+    /// Read one frame, returning the packet id and a cursor over the rest of it
+    pub async fn read_frame<S: AsyncReadExt + Unpin>(
+        &self,
+        source: &mut S,
+    ) -> Result<(i32, Cursor<Vec<u8>>), Error> {
+        let length: usize = read_atom::read_varint_async(source).await?.try_into()?;
+        if length > self.max_length {
+            return Err(Error::framing(format!(
+                "Frame is {} bytes, more than the limit of {}",
+                length, self.max_length
+            )));
+        }
+        let mut buf = vec![0; length];
+        if length > 0 {
+            source.read_exact(&mut buf).await?;
+        }
+        let buf = match self.threshold {
+            None => buf,
+            Some(threshold) => decompress(buf, threshold)?,
+        };
+        let mut cursor = Cursor::new(buf);
+        let packet_id = read_atom::read_varint(&mut cursor)?;
+        Ok((packet_id, cursor))
+    }
+
+    /// Read one packet, decoded according to what `state` the connection is in
+    pub async fn read<S: AsyncReadExt + Unpin>(
+        &self,
+        source: &mut S,
+        state: ConnectionState,
+    ) -> Result<Serverbound, Error> {
+        let (packet_id, mut cursor) = self.read_frame(source).await?;
+        trace!("reading packet type {:#} while {:?}", packet_id, state);
+        decode(state, packet_id, &mut cursor)
+    }
+
+    /// Read one packet that has to be a `P`
+    pub async fn read_packet<P: Packet, S: AsyncReadExt + Unpin>(
+        &self,
+        source: &mut S,
+    ) -> Result<P, Error> {
+        match self.read_frame(source).await? {
+            (id, mut cursor) if id == P::ID => P::decode(&mut cursor),
+            (id, _) => Err(Error::UnknownPacket(id)),
+        }
+    }
+
+    pub async fn write<P: Packet, W: AsyncWriteExt + Unpin>(
+        &self,
+        packet: &P,
+        dest: &mut W,
+    ) -> Result<(), Error> {
+        let mut buf = vec![];
+        write_atom::write_varint(P::ID, &mut buf)?; // Every packet has an ID so write it for the packet
+        packet.encode(&mut buf)?;
+        let buf = match self.threshold {
+            None => buf,
+            Some(threshold) => compress(buf, threshold)?,
+        };
+        let mut frame = vec![];
+        write_atom::write_varint(buf.len().try_into()?, &mut frame)?;
+        frame.extend(buf);
+        dest.write_all(&frame).await?;
+        Ok(())
+    }
+}
+
+fn compress(data: Vec<u8>, threshold: usize) -> Result<Vec<u8>, Error> {
+    let mut buf = vec![];
+    if data.len() < threshold {
+        write_atom::write_varint(0, &mut buf)?;
+        buf.extend(data);
+        return Ok(buf);
+    }
+    write_atom::write_varint(data.len().try_into()?, &mut buf)?;
+    let mut encoder = ZlibEncoder::new(buf, Compression::default());
+    encoder.write_all(&data)?;
+    Ok(encoder.finish()?)
+}
+
+fn decompress(frame: Vec<u8>, threshold: usize) -> Result<Vec<u8>, Error> {
+    let mut cursor = Cursor::new(frame);
+    let data_length = read_atom::read_length(&mut cursor)?;
+    if data_length == 0 {
+        let start = cursor.position() as usize;
+        let mut frame = cursor.into_inner();
+        frame.drain(..start);
+        return Ok(frame);
+    }
+    if data_length < threshold {
+        return Err(Error::framing(format!(
+            "Compressed a {} byte packet, under the threshold of {}",
+            data_length, threshold
+        )));
+    }
+    if data_length > MAX_DATA_LENGTH {
+        return Err(Error::framing(format!(
+            "Compressed packet is {} bytes, more than the limit of {}",
+            data_length, MAX_DATA_LENGTH
+        )));
+    }
+    // Grown as it decompresses, since the length is only what the other side claims. Read one
+    // byte past it so a packet that decompresses to more gets caught.
+    let mut data = vec![];
+    ZlibDecoder::new(cursor)
+        .take(data_length as u64 + 1)
+        .read_to_end(&mut data)?;
+    if data.len() != data_length {
+        return Err(Error::framing(format!(
+            "Compressed packet said it was {} bytes but was {}",
+            data_length,
+            data.len()
+        )));
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::packet::{LoginStart, SetCompression, StatusResponse};
+
+    fn big_status() -> StatusResponse {
+        StatusResponse {
+            json: "a".repeat(1000),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_compressed_frames() -> Result<(), Error> {
+        let framing = Framing::compressed(256);
+        let mut buf = vec![];
+        framing
+            .write(
+                &LoginStart {
+                    name: "bob".to_owned(),
+                    rest: vec![],
+                },
+                &mut buf,
+            )
+            .await?;
+        // Under the threshold: a data length of 0, then the packet as usual
+        assert_eq!(vec![0x06, 0x00, 0x00, 0x03, b'b', b'o', b'b'], buf);
+
+        let mut big = vec![];
+        framing.write(&big_status(), &mut big).await?;
+        assert!(big.len() < 100, "{} bytes isn't compressed", big.len());
+        buf.extend(big);
+
+        let mut source = Cursor::new(buf);
+        let login: LoginStart = framing.read_packet(&mut source).await?;
+        assert_eq!("bob", login.name);
+        assert_eq!(big_status(), framing.read_packet(&mut source).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_switch_mid_stream() -> Result<(), Error> {
+        // The server's side
+        let mut framing = Framing::default();
+        let mut buf = vec![];
+        let set_compression = SetCompression { threshold: 64 };
+        framing.write(&set_compression, &mut buf).await?;
+        framing.set_compression(set_compression.threshold);
+        framing.write(&big_status(), &mut buf).await?;
+        framing.set_compression(-1);
+        framing.write(&big_status(), &mut buf).await?;
+
+        // The client's side, following along
+        let mut framing = Framing::default();
+        let mut source = Cursor::new(buf);
+        let set_compression: SetCompression = framing.read_packet(&mut source).await?;
+        framing.set_compression(set_compression.threshold);
+        assert_eq!(Some(64), framing.threshold());
+        assert_eq!(big_status(), framing.read_packet(&mut source).await?);
+        framing.set_compression(-1);
+        assert_eq!(big_status(), framing.read_packet(&mut source).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_max_length() -> Result<(), Error> {
+        let mut buf = vec![];
+        Framing::default().write(&big_status(), &mut buf).await?;
+        let framing = Framing::default().with_max_length(100);
+        let result: Result<StatusResponse, _> = framing.read_packet(&mut Cursor::new(buf)).await;
+        assert!(matches!(result, Err(Error::Framing(_))));
+        // Nothing gets read past a length that's too long, or negative
+        let mut huge = vec![];
+        write_atom::write_varint(i32::MAX, &mut huge)?;
+        assert!(Framing::default()
+            .read_frame(&mut Cursor::new(huge))
+            .await
+            .is_err());
+        let mut negative = vec![];
+        write_atom::write_varint(-1, &mut negative)?;
+        assert!(Framing::default()
+            .read_frame(&mut Cursor::new(negative))
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_bad_compression() -> Result<(), Error> {
+        let compressed = compress(vec![0; 100], 10)?;
+        assert_eq!(vec![0; 100], decompress(compressed.clone(), 10)?);
+        // Compressing something the other side should have left alone
+        assert!(decompress(compressed.clone(), 200).is_err());
+        // Lying about the size
+        let mut lying = vec![];
+        write_atom::write_varint(50, &mut lying)?;
+        lying.extend(&compressed[1..]);
+        assert!(decompress(lying, 10).is_err());
+        // Claiming the most it can, which mustn't get that much set aside up front
+        let mut claiming = vec![];
+        write_atom::write_varint(MAX_DATA_LENGTH as i32, &mut claiming)?;
+        claiming.extend(&compressed[1..]);
+        assert!(decompress(claiming, 10).is_err());
+        // A zip bomb
+        let mut bomb = vec![];
+        write_atom::write_varint(MAX_DATA_LENGTH as i32 + 1, &mut bomb)?;
+        assert!(decompress(bomb, 10).is_err());
+        Ok(())
+    }
+}
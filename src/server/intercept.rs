@@ -0,0 +1,91 @@
+use crate::error::Error;
+use crate::server::framing::Framing;
+use crate::server::packet::{Handshake, LoginStart};
+use crate::server::read::packet::{ConnectionState, Serverbound};
+use crate::util::record::Recorder;
+use std::net::SocketAddr;
+use tokio::net::TcpStream;
+
+/// A connection whose handshake (and login start, for logins) we've already read
+pub struct Intercepted {
+    pub handshake: Handshake,
+    /// Only for logins
+    pub login_start: Option<LoginStart>,
+    /// Where the connection came from. The other end of the socket unless something (like a
+    /// PROXY header) says otherwise.
+    pub peer: SocketAddr,
+    socket: TcpStream,
+    consumed: Vec<u8>,
+}
+
+impl Intercepted {
+    pub fn is_login(&self) -> bool {
+        self.login_start.is_some()
+    }
+
+    /// The name of the player logging in
+    pub fn player(&self) -> Option<&str> {
+        self.login_start.as_ref().map(|login| login.name.as_str())
+    }
+
+    /// The UUID of the player logging in, if their client is new enough to send it. Nothing
+    /// checks it, so it's only as trustworthy as the name.
+    pub fn uuid(&self) -> Option<u128> {
+        let protocol = self.handshake.protocol_version;
+        self.login_start
+            .as_ref()
+            .and_then(|login| login.uuid(protocol))
+    }
+
+    /// For carrying on the conversation ourselves. Anything read or written through this
+    /// won't be replayed.
+    pub fn socket_mut(&mut self) -> &mut TcpStream {
+        &mut self.socket
+    }
+
+    /// The socket, and everything we read from it
+    pub fn into_parts(self) -> (TcpStream, Vec<u8>) {
+        (self.socket, self.consumed)
+    }
+}
+
+/// Read just enough of a connection to know what the client wants, keeping the bytes so the
+/// real server can see them too
+pub async fn intercept(socket: TcpStream, framing: Framing) -> Result<Intercepted, Error> {
+    let peer = socket.peer_addr()?;
+    let mut recorder = Recorder::new(socket);
+    let handshake = match framing
+        .read(&mut recorder, ConnectionState::Handshaking)
+        .await?
+    {
+        Serverbound::Handshake(handshake) => handshake,
+        other => {
+            return Err(Error::protocol(format!(
+                "Expected a handshake, got {:?}",
+                other
+            )))
+        }
+    };
+    let login_start = match handshake.next_state()? {
+        ConnectionState::Login => {
+            match framing.read(&mut recorder, ConnectionState::Login).await? {
+                Serverbound::LoginStart(login_start) => Some(login_start),
+                other => {
+                    return Err(Error::protocol(format!(
+                        "Expected login start, got {:?}",
+                        other
+                    )))
+                }
+            }
+        }
+        _ => None,
+    };
+    let (socket, consumed) = recorder.into_parts();
+    Ok(Intercepted {
+        handshake,
+        login_start,
+        peer,
+        socket,
+        consumed,
+    })
+}
@@ -0,0 +1,212 @@
+/*
+    Server list pings from before 1.7, which aren't VarInt framed at all. Old clients (and plenty
+    of server list scanners) start with 0xFE, which a modern handshake never does since no
+    handshake is long enough to need that length.
+        Beta 1.8 - 1.3: 0xFE, answered with a kick packet holding "motd§online§max"
+        1.4 - 1.5:      0xFE 0x01, answered with "§1\0protocol\0version\0motd\0online\0max"
+        1.6:            0xFE 0x01 then an MC|PingHost plugin message, answered the same as 1.4
+    Kick packets are 0xFF, the string length in UTF-16 code units as a short, then UTF-16BE.
+*/
+
+use crate::error::Error;
+use std::convert::TryInto;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+const LEGACY_PING: u8 = 0xFE;
+const PLUGIN_MESSAGE: u8 = 0xFA;
+const KICK: u8 = 0xFF;
+// Beta clients send a lone 0xFE, so we can only tell them apart by waiting to see if more comes
+const FOLLOW_UP_TIMEOUT: Duration = Duration::from_millis(500);
+// No pre-1.7 client can join a modern server, so always tell them their version won't work
+const INCOMPATIBLE_PROTOCOL: i32 = 127;
+
+/// Whether the connection starts with a legacy ping, without reading anything from it
+pub async fn is_legacy_ping(socket: &TcpStream) -> Result<bool, Error> {
+    let mut first = [0; 1];
+    let read = socket.peek(&mut first).await?;
+    Ok(read == 1 && first[0] == LEGACY_PING)
+}
+
+/// What to show in an old client's server list
+pub struct LegacyStatus<'a> {
+    pub version_name: &'a str,
+    /// Can have § codes, old clients understand those
+    pub motd: &'a str,
+    pub online: u32,
+    pub max: u32,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+enum LegacyPing {
+    Beta,
+    V1_4,
+    V1_6 { protocol: u8, host: String },
+}
+
+/// Read a legacy ping and answer it
+pub async fn answer<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: &mut S,
+    status: &LegacyStatus<'_>,
+) -> Result<(), Error> {
+    let ping = read_ping(socket).await?;
+    debug!("Got a legacy {:?} ping", ping);
+    socket
+        .write_all(&kick_packet(&response(&ping, status))?)
+        .await?;
+    Ok(())
+}
+
+async fn read_ping<S: AsyncRead + Unpin>(socket: &mut S) -> Result<LegacyPing, Error> {
+    if socket.read_u8().await? != LEGACY_PING {
+        return Err(Error::protocol("Not a legacy ping"));
+    }
+    match timeout(FOLLOW_UP_TIMEOUT, socket.read_u8()).await {
+        Err(_) => return Ok(LegacyPing::Beta),
+        Ok(byte) => expect_byte(byte?, 0x01)?,
+    }
+    match timeout(FOLLOW_UP_TIMEOUT, socket.read_u8()).await {
+        Err(_) => return Ok(LegacyPing::V1_4),
+        Ok(byte) => expect_byte(byte?, PLUGIN_MESSAGE)?,
+    }
+    // It has to be read even though we don't need much of it, otherwise closing the connection
+    // with it unread resets it and the client might not see our answer
+    let channel = read_legacy_string(socket).await?;
+    if channel != "MC|PingHost" {
+        return Err(Error::protocol(format!(
+            "Unexpected legacy plugin channel {}",
+            channel
+        )));
+    }
+    let length = socket.read_u16().await?;
+    let mut data = vec![0; length as usize];
+    socket.read_exact(&mut data).await?;
+    let mut data = &data[..];
+    let protocol = data.read_u8().await?;
+    let host = read_legacy_string(&mut data).await?;
+    Ok(LegacyPing::V1_6 { protocol, host })
+}
+
+fn expect_byte(byte: u8, expected: u8) -> Result<(), Error> {
+    if byte != expected {
+        return Err(Error::protocol(format!(
+            "Expected {:#x} in a legacy ping, got {:#x}",
+            expected, byte
+        )));
+    }
+    Ok(())
+}
+
+async fn read_legacy_string<S: AsyncRead + Unpin>(source: &mut S) -> Result<String, Error> {
+    let length = source.read_u16().await?;
+    let mut units = Vec::with_capacity(length as usize);
+    for _ in 0..length {
+        units.push(source.read_u16().await?);
+    }
+    Ok(String::from_utf16(&units)?)
+}
+
+fn response(ping: &LegacyPing, status: &LegacyStatus<'_>) -> String {
+    // There's only room for one line in an old server list
+    let motd = status.motd.replace('\n', " ");
+    match ping {
+        LegacyPing::Beta => format!("{}§{}§{}", strip_codes(&motd), status.online, status.max),
+        _ => format!(
+            "§1\0{}\0{}\0{}\0{}\0{}",
+            INCOMPATIBLE_PROTOCOL, status.version_name, motd, status.online, status.max
+        ),
+    }
+}
+
+// Beta clients split the response on §, so they can't have any color codes
+fn strip_codes(text: &str) -> String {
+    let mut stripped = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '§' {
+            chars.next();
+        } else {
+            stripped.push(c);
+        }
+    }
+    stripped
+}
+
+fn kick_packet(text: &str) -> Result<Vec<u8>, Error> {
+    let units: Vec<u16> = text.encode_utf16().collect();
+    let mut packet = vec![KICK];
+    packet.extend_from_slice(&TryInto::<u16>::try_into(units.len())?.to_be_bytes());
+    for unit in units {
+        packet.extend_from_slice(&unit.to_be_bytes());
+    }
+    Ok(packet)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    const STATUS: LegacyStatus<'static> = LegacyStatus {
+        version_name: "1.16.5",
+        motd: "§aFake!\nSleeping",
+        online: 0,
+        max: 20,
+    };
+
+    // Send a ping from the "client" side and return the decoded kick string
+    async fn ping(request: &[u8]) -> Result<String, Error> {
+        let (mut client, mut server) = duplex(1024);
+        client.write_all(request).await?;
+        answer(&mut server, &STATUS).await?;
+        assert_eq!(KICK, client.read_u8().await?);
+        read_legacy_string(&mut client).await
+    }
+
+    fn legacy_string(text: &str) -> Vec<u8> {
+        let mut buf = kick_packet(text).unwrap();
+        buf.remove(0);
+        buf
+    }
+
+    #[tokio::test]
+    async fn test_beta_ping() -> Result<(), Error> {
+        assert_eq!("Fake! Sleeping§0§20", ping(&[0xFE]).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_1_4_ping() -> Result<(), Error> {
+        let response = ping(&[0xFE, 0x01]).await?;
+        assert_eq!(
+            vec!["§1", "127", "1.16.5", "§aFake! Sleeping", "0", "20"],
+            response.split('\0').collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_1_6_ping() -> Result<(), Error> {
+        let mut request = vec![0xFE, 0x01, PLUGIN_MESSAGE];
+        request.extend(legacy_string("MC|PingHost"));
+        let mut data = vec![78];
+        data.extend(legacy_string("localhost"));
+        data.extend_from_slice(&25565i32.to_be_bytes());
+        request.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        request.extend(data);
+
+        let (mut client, mut server) = duplex(1024);
+        client.write_all(&request).await?;
+        assert_eq!(
+            LegacyPing::V1_6 {
+                protocol: 78,
+                host: "localhost".to_owned()
+            },
+            read_ping(&mut server).await?
+        );
+        assert!(ping(&request).await?.starts_with("§1\x00127\x00"));
+        Ok(())
+    }
+}
@@ -0,0 +1,260 @@
+/*
+    Keeps a flood of connections from taking the fake server down. Each connection has to get past
+    the connection cap and its address's connection rate as soon as it's accepted, and past the
+    ping rate once it says it's pinging. Behind a load balancer the rate waits until the PROXY
+    header says whose address it is. Anything over a limit is closed without a word and
+    counted, as are connections that are too slow or send too much.
+*/
+
+use super::framing::Framing;
+use crate::config::LimitsConfig;
+use crate::error::Error;
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::time::timeout;
+
+const MINUTE: Duration = Duration::from_secs(60);
+
+/// How many connections have been closed for each reason
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct Counts {
+    pub too_many_connections: u64,
+    pub connecting_too_often: u64,
+    pub pinging_too_often: u64,
+    pub too_slow: u64,
+    pub malformed: u64,
+}
+
+#[derive(Debug)]
+pub struct Limiter {
+    config: LimitsConfig,
+    open: AtomicUsize,
+    connections: RateLimit,
+    pings: RateLimit,
+    counts: Mutex<Counts>,
+}
+
+/// Held for as long as a connection is open, so it counts towards max_connections
+#[derive(Debug)]
+pub struct Permit {
+    limiter: Arc<Limiter>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.limiter.open.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Default for Limiter {
+    fn default() -> Self {
+        Limiter::new(LimitsConfig::default())
+    }
+}
+
+impl Limiter {
+    pub fn new(config: LimitsConfig) -> Self {
+        Limiter {
+            connections: RateLimit::new(config.connections_per_minute),
+            pings: RateLimit::new(config.pings_per_minute),
+            config,
+            open: AtomicUsize::new(0),
+            counts: Mutex::new(Counts::default()),
+        }
+    }
+
+    /// Let a new connection from `ip` in, unless it's one too many
+    pub fn admit(self: &Arc<Self>, ip: IpAddr) -> Option<Permit> {
+        let permit = self.reserve(ip)?;
+        self.rate_limit(permit, ip)
+    }
+
+    /// Count a connection towards max_connections without holding `ip` to the connection rate,
+    /// for when `ip` is a load balancer that everyone comes through. Once it's known where the
+    /// connection is really from, rate_limit it.
+    pub fn reserve(self: &Arc<Self>, ip: IpAddr) -> Option<Permit> {
+        if self.open.fetch_add(1, Ordering::SeqCst) >= self.config.max_connections {
+            self.open.fetch_sub(1, Ordering::SeqCst);
+            self.count(ip, "too many connections", |counts| {
+                &mut counts.too_many_connections
+            });
+            return None;
+        }
+        // From here on dropping the permit gives the connection back
+        Some(Permit {
+            limiter: self.clone(),
+        })
+    }
+
+    /// Hold a connection that's already counted to the connection rate for `ip`
+    pub fn rate_limit(&self, permit: Permit, ip: IpAddr) -> Option<Permit> {
+        if !self.connections.allow(ip, Instant::now()) {
+            self.count(ip, "connecting too often", |counts| {
+                &mut counts.connecting_too_often
+            });
+            return None;
+        }
+        Some(permit)
+    }
+
+    /// Whether `ip` can have another server list ping
+    pub fn allow_ping(&self, ip: IpAddr) -> bool {
+        let allowed = self.pings.allow(ip, Instant::now());
+        if !allowed {
+            self.count(ip, "pinging too often", |counts| {
+                &mut counts.pinging_too_often
+            });
+        }
+        allowed
+    }
+
+    /// How to read what clients send, so a made up length can't make us allocate megabytes
+    pub fn framing(&self) -> Framing {
+        Framing::default().with_max_length(self.config.max_frame_length)
+    }
+
+    pub fn handshake_timeout(&self) -> Duration {
+        self.config.handshake_timeout()
+    }
+
+    pub fn read_timeout(&self) -> Duration {
+        self.config.read_timeout()
+    }
+
+    /// Log a connection that went wrong, counting it if it's one we closed for being too slow or
+    /// sending too much
+    pub fn failed(&self, e: &Error) {
+        let mut counts = self.counts.lock().unwrap();
+        let count = match e {
+            Error::Timeout(_) => &mut counts.too_slow,
+            Error::Framing(_) => &mut counts.malformed,
+            _ => return error!("{}", e),
+        };
+        *count += 1;
+        debug!("Closed a connection: {} ({} so far)", e, count);
+    }
+
+    // Only tests look at these all at once, the log has them as they go up
+    #[allow(dead_code)]
+    pub fn counts(&self) -> Counts {
+        *self.counts.lock().unwrap()
+    }
+
+    fn count(&self, ip: IpAddr, reason: &str, counter: impl FnOnce(&mut Counts) -> &mut u64) {
+        let mut counts = self.counts.lock().unwrap();
+        let count = counter(&mut counts);
+        *count += 1;
+        debug!(
+            "Closed a connection from {}: {} ({} so far)",
+            ip, reason, count
+        );
+    }
+}
+
+/// Wait `deadline` at most for what the client says next
+pub async fn within<T>(
+    deadline: Duration,
+    what: &str,
+    future: impl Future<Output = Result<T, Error>>,
+) -> Result<T, Error> {
+    match timeout(deadline, future).await {
+        Ok(result) => result,
+        Err(_) => Err(Error::Timeout(format!("waiting for {}", what))),
+    }
+}
+
+// How many times each address has done something in the current minute
+#[derive(Debug)]
+struct RateLimit {
+    per_minute: u32,
+    windows: Mutex<HashMap<IpAddr, (Instant, u32)>>,
+}
+
+impl RateLimit {
+    fn new(per_minute: u32) -> Self {
+        RateLimit {
+            per_minute,
+            windows: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn allow(&self, ip: IpAddr, now: Instant) -> bool {
+        let mut windows = self.windows.lock().unwrap();
+        // Forget about everyone whose minute is up, so the map only holds recent addresses
+        windows.retain(|_, (started, _)| now.saturating_duration_since(*started) < MINUTE);
+        let (_, count) = windows.entry(ip).or_insert((now, 0));
+        *count += 1;
+        *count <= self.per_minute
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let limit = RateLimit::new(2);
+        let start = Instant::now();
+        assert!(limit.allow(ip("10.0.0.1"), start));
+        assert!(limit.allow(ip("10.0.0.1"), start + Duration::from_secs(10)));
+        assert!(!limit.allow(ip("10.0.0.1"), start + Duration::from_secs(20)));
+        assert!(limit.allow(ip("10.0.0.2"), start + Duration::from_secs(20)));
+        // A new minute
+        assert!(limit.allow(ip("10.0.0.1"), start + MINUTE));
+    }
+
+    #[test]
+    fn test_max_connections() {
+        let limiter = Arc::new(Limiter::new(LimitsConfig {
+            max_connections: 2,
+            ..LimitsConfig::default()
+        }));
+        let first = limiter.admit(ip("10.0.0.1"));
+        let second = limiter.admit(ip("10.0.0.2"));
+        assert!(first.is_some() && second.is_some());
+        assert!(limiter.admit(ip("10.0.0.3")).is_none());
+        drop(first);
+        assert!(limiter.admit(ip("10.0.0.3")).is_some());
+        assert_eq!(1, limiter.counts().too_many_connections);
+    }
+
+    #[test]
+    fn test_connection_rate() {
+        let limiter = Arc::new(Limiter::new(LimitsConfig {
+            connections_per_minute: 1,
+            ..LimitsConfig::default()
+        }));
+        assert!(limiter.admit(ip("10.0.0.1")).is_some());
+        assert!(limiter.admit(ip("10.0.0.1")).is_none());
+        assert_eq!(1, limiter.counts().connecting_too_often);
+        // Refused connections don't hold on to a slot
+        assert_eq!(0, limiter.open.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_behind_balancer() {
+        let limiter = Arc::new(Limiter::new(LimitsConfig {
+            max_connections: 2,
+            connections_per_minute: 1,
+            ..LimitsConfig::default()
+        }));
+        let balancer = ip("10.0.0.1");
+        // The balancer isn't rate limited, but it still counts towards the cap
+        let first = limiter.reserve(balancer).unwrap();
+        let second = limiter.reserve(balancer).unwrap();
+        assert!(limiter.reserve(balancer).is_none());
+        let first = limiter.rate_limit(first, ip("203.0.113.7"));
+        assert!(first.is_some());
+        assert!(limiter.rate_limit(second, ip("203.0.113.7")).is_none());
+        assert_eq!(1, limiter.open.load(Ordering::SeqCst));
+    }
+}
@@ -1,3 +1,18 @@
+#[macro_use]
+pub mod codec;
+pub mod chat;
+pub mod client;
+pub mod encryption;
 pub mod fake_server;
+pub mod favicon;
+pub mod framing;
+pub mod intercept;
+pub mod legacy;
+pub mod limits;
+pub mod nbt;
+pub mod packet;
 pub mod read;
+pub mod session;
+pub mod status;
+pub mod version;
 pub mod write;
@@ -0,0 +1,506 @@
+/*
+    NBT, Minecraft's binary tree format. Files (and packets before 1.20.2) start with a named root:
+    the root's tag id, a name that's almost always empty, then its payload. Packets since 1.20.2
+    use the network variant, which drops the name. Strings are Java's "modified UTF-8", where nul
+    is two bytes and anything outside the BMP is a pair of encoded surrogates.
+
+    Everything read is bounded by a Limits, since it usually comes from someone we don't trust.
+*/
+
+use super::codec::{Decode, Encode};
+use crate::error::Error;
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::io::{Read, Write};
+
+const END: u8 = 0;
+const BYTE: u8 = 1;
+const SHORT: u8 = 2;
+const INT: u8 = 3;
+const LONG: u8 = 4;
+const FLOAT: u8 = 5;
+const DOUBLE: u8 = 6;
+const BYTE_ARRAY: u8 = 7;
+const STRING: u8 = 8;
+const LIST: u8 = 9;
+const COMPOUND: u8 = 10;
+const INT_ARRAY: u8 = 11;
+const LONG_ARRAY: u8 = 12;
+
+/// One tag's value. End isn't here since it only marks where a compound stops (or that an empty
+/// list has no element type).
+// No packet the facade handles has NBT in it yet, so only tests use this and the functions below
+#[allow(dead_code)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum Nbt {
+    Byte(i8),
+    Short(i16),
+    Int(i32),
+    Long(i64),
+    Float(f32),
+    Double(f64),
+    ByteArray(Vec<i8>),
+    String(String),
+    /// Every element has to be the same kind of tag
+    List(Vec<Nbt>),
+    Compound(BTreeMap<String, Nbt>),
+    IntArray(Vec<i32>),
+    LongArray(Vec<i64>),
+}
+
+impl Nbt {
+    fn tag(&self) -> u8 {
+        match self {
+            Nbt::Byte(_) => BYTE,
+            Nbt::Short(_) => SHORT,
+            Nbt::Int(_) => INT,
+            Nbt::Long(_) => LONG,
+            Nbt::Float(_) => FLOAT,
+            Nbt::Double(_) => DOUBLE,
+            Nbt::ByteArray(_) => BYTE_ARRAY,
+            Nbt::String(_) => STRING,
+            Nbt::List(_) => LIST,
+            Nbt::Compound(_) => COMPOUND,
+            Nbt::IntArray(_) => INT_ARRAY,
+            Nbt::LongArray(_) => LONG_ARRAY,
+        }
+    }
+
+    /// A compound from (name, value) pairs
+    #[allow(dead_code)]
+    pub fn compound<K: Into<String>>(entries: impl IntoIterator<Item = (K, Nbt)>) -> Self {
+        Nbt::Compound(
+            entries
+                .into_iter()
+                .map(|(name, value)| (name.into(), value))
+                .collect(),
+        )
+    }
+}
+
+/// How much we're willing to read. The defaults are the vanilla server's limits for packets.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Limits {
+    pub max_bytes: usize,
+    pub max_depth: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_bytes: 2 * 1024 * 1024,
+            max_depth: 512,
+        }
+    }
+}
+
+/// Read NBT with a named root, like a file or an older packet. Returns the root's name too.
+#[allow(dead_code)]
+pub fn read_named(source: &mut impl Read, limits: Limits) -> Result<(String, Nbt), Error> {
+    let mut reader = Reader::new(source, limits);
+    let tag = reader.u8()?;
+    if tag == END {
+        return Err(Error::framing("NBT root can't be an end tag"));
+    }
+    let name = reader.string()?;
+    Ok((name, reader.payload(tag, 0)?))
+}
+
+/// Read network NBT, which has no root name. An end tag in place of the root means there's no
+/// value at all.
+#[allow(dead_code)]
+pub fn read_network(source: &mut impl Read, limits: Limits) -> Result<Option<Nbt>, Error> {
+    let mut reader = Reader::new(source, limits);
+    match reader.u8()? {
+        END => Ok(None),
+        tag => Ok(Some(reader.payload(tag, 0)?)),
+    }
+}
+
+#[allow(dead_code)]
+pub fn write_named(name: &str, value: &Nbt, sink: &mut impl Write) -> Result<(), Error> {
+    sink.write_all(&[value.tag()])?;
+    write_string(name, sink)?;
+    write_payload(value, sink)
+}
+
+#[allow(dead_code)]
+pub fn write_network(value: Option<&Nbt>, sink: &mut impl Write) -> Result<(), Error> {
+    match value {
+        Some(value) => {
+            sink.write_all(&[value.tag()])?;
+            write_payload(value, sink)
+        }
+        None => Ok(sink.write_all(&[END])?),
+    }
+}
+
+/// Packets carry network NBT, read with the default limits
+impl Encode for Nbt {
+    fn encode(&self, sink: &mut impl Write) -> Result<(), Error> {
+        write_network(Some(self), sink)
+    }
+}
+
+impl Decode for Nbt {
+    fn decode(source: &mut impl Read) -> Result<Self, Error> {
+        read_network(source, Limits::default())?
+            .ok_or_else(|| Error::framing("Expected NBT, got an end tag"))
+    }
+}
+
+struct Reader<'a, R> {
+    source: &'a mut R,
+    remaining: usize,
+    max_depth: usize,
+}
+
+impl<'a, R: Read> Reader<'a, R> {
+    fn new(source: &'a mut R, limits: Limits) -> Self {
+        Reader {
+            source,
+            remaining: limits.max_bytes,
+            max_depth: limits.max_depth,
+        }
+    }
+
+    // Count bytes against the limit before reading them, so a huge length fails before we try to
+    // allocate for it
+    fn charge(&mut self, bytes: usize) -> Result<(), Error> {
+        self.remaining = self
+            .remaining
+            .checked_sub(bytes)
+            .ok_or_else(|| Error::framing("NBT is too big"))?;
+        Ok(())
+    }
+
+    fn bytes<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        self.charge(N)?;
+        let mut buf = [0; N];
+        self.source.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.bytes::<1>()?[0])
+    }
+
+    fn length(&mut self) -> Result<usize, Error> {
+        let length = i32::from_be_bytes(self.bytes()?);
+        length
+            .try_into()
+            .map_err(|_| Error::framing(format!("Negative NBT length {}", length)))
+    }
+
+    fn array<T, const N: usize>(&mut self, from: fn([u8; N]) -> T) -> Result<Vec<T>, Error> {
+        let length = self.length()?;
+        let size = length
+            .checked_mul(N)
+            .ok_or_else(|| Error::framing("NBT is too big"))?;
+        self.charge(size)?;
+        let mut buf = vec![0; size];
+        self.source.read_exact(&mut buf)?;
+        Ok(buf
+            .chunks_exact(N)
+            .map(|chunk| from(chunk.try_into().unwrap()))
+            .collect())
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        let length = u16::from_be_bytes(self.bytes()?) as usize;
+        self.charge(length)?;
+        let mut buf = vec![0; length];
+        self.source.read_exact(&mut buf)?;
+        decode_mutf8(&buf)
+    }
+
+    fn nest(&self, depth: usize) -> Result<usize, Error> {
+        if depth >= self.max_depth {
+            return Err(Error::framing("NBT is nested too deeply"));
+        }
+        Ok(depth + 1)
+    }
+
+    fn payload(&mut self, tag: u8, depth: usize) -> Result<Nbt, Error> {
+        Ok(match tag {
+            BYTE => Nbt::Byte(i8::from_be_bytes(self.bytes()?)),
+            SHORT => Nbt::Short(i16::from_be_bytes(self.bytes()?)),
+            INT => Nbt::Int(i32::from_be_bytes(self.bytes()?)),
+            LONG => Nbt::Long(i64::from_be_bytes(self.bytes()?)),
+            FLOAT => Nbt::Float(f32::from_be_bytes(self.bytes()?)),
+            DOUBLE => Nbt::Double(f64::from_be_bytes(self.bytes()?)),
+            BYTE_ARRAY => Nbt::ByteArray(self.array(i8::from_be_bytes)?),
+            STRING => Nbt::String(self.string()?),
+            LIST => {
+                let depth = self.nest(depth)?;
+                let element_tag = self.u8()?;
+                let length = self.length()?;
+                if element_tag == END && length > 0 {
+                    return Err(Error::framing("NBT list of end tags"));
+                }
+                // Grown as elements are read, so a made up length (at every level of nesting)
+                // doesn't reserve anything up front
+                let mut elements = Vec::new();
+                for _ in 0..length {
+                    elements.push(self.payload(element_tag, depth)?);
+                }
+                Nbt::List(elements)
+            }
+            COMPOUND => {
+                let depth = self.nest(depth)?;
+                let mut entries = BTreeMap::new();
+                loop {
+                    let tag = self.u8()?;
+                    if tag == END {
+                        break;
+                    }
+                    let name = self.string()?;
+                    entries.insert(name, self.payload(tag, depth)?);
+                }
+                Nbt::Compound(entries)
+            }
+            INT_ARRAY => Nbt::IntArray(self.array(i32::from_be_bytes)?),
+            LONG_ARRAY => Nbt::LongArray(self.array(i64::from_be_bytes)?),
+            other => return Err(Error::framing(format!("Unknown NBT tag {}", other))),
+        })
+    }
+}
+
+fn write_payload(value: &Nbt, sink: &mut impl Write) -> Result<(), Error> {
+    match value {
+        Nbt::Byte(byte) => sink.write_all(&byte.to_be_bytes())?,
+        Nbt::Short(short) => sink.write_all(&short.to_be_bytes())?,
+        Nbt::Int(int) => sink.write_all(&int.to_be_bytes())?,
+        Nbt::Long(long) => sink.write_all(&long.to_be_bytes())?,
+        Nbt::Float(float) => sink.write_all(&float.to_be_bytes())?,
+        Nbt::Double(double) => sink.write_all(&double.to_be_bytes())?,
+        Nbt::ByteArray(bytes) => {
+            write_length(bytes.len(), sink)?;
+            let bytes: Vec<u8> = bytes.iter().map(|byte| *byte as u8).collect();
+            sink.write_all(&bytes)?;
+        }
+        Nbt::String(string) => write_string(string, sink)?,
+        Nbt::List(elements) => {
+            let element_tag = elements.first().map_or(END, Nbt::tag);
+            if elements.iter().any(|element| element.tag() != element_tag) {
+                return Err(Error::framing("NBT list elements have different types"));
+            }
+            sink.write_all(&[element_tag])?;
+            write_length(elements.len(), sink)?;
+            for element in elements {
+                write_payload(element, sink)?;
+            }
+        }
+        Nbt::Compound(entries) => {
+            for (name, value) in entries {
+                sink.write_all(&[value.tag()])?;
+                write_string(name, sink)?;
+                write_payload(value, sink)?;
+            }
+            sink.write_all(&[END])?;
+        }
+        Nbt::IntArray(ints) => {
+            write_length(ints.len(), sink)?;
+            for int in ints {
+                sink.write_all(&int.to_be_bytes())?;
+            }
+        }
+        Nbt::LongArray(longs) => {
+            write_length(longs.len(), sink)?;
+            for long in longs {
+                sink.write_all(&long.to_be_bytes())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn write_length(length: usize, sink: &mut impl Write) -> Result<(), Error> {
+    let length: i32 = length.try_into()?;
+    Ok(sink.write_all(&length.to_be_bytes())?)
+}
+
+fn write_string(string: &str, sink: &mut impl Write) -> Result<(), Error> {
+    let encoded = encode_mutf8(string);
+    let length: u16 = encoded.len().try_into()?;
+    sink.write_all(&length.to_be_bytes())?;
+    Ok(sink.write_all(&encoded)?)
+}
+
+fn encode_mutf8(string: &str) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(string.len());
+    for unit in string.encode_utf16() {
+        match unit {
+            0x01..=0x7F => encoded.push(unit as u8),
+            // Including nul, which never appears as a 0 byte
+            0x00 | 0x80..=0x7FF => {
+                encoded.push(0xC0 | (unit >> 6) as u8);
+                encoded.push(0x80 | (unit & 0x3F) as u8);
+            }
+            _ => {
+                encoded.push(0xE0 | (unit >> 12) as u8);
+                encoded.push(0x80 | ((unit >> 6) & 0x3F) as u8);
+                encoded.push(0x80 | (unit & 0x3F) as u8);
+            }
+        }
+    }
+    encoded
+}
+
+fn decode_mutf8(bytes: &[u8]) -> Result<String, Error> {
+    let bad = || Error::framing("Bad modified UTF-8 in NBT string");
+    let mut units = Vec::with_capacity(bytes.len());
+    let mut bytes = bytes.iter();
+    let continuation = |bytes: &mut std::slice::Iter<u8>| match bytes.next() {
+        Some(byte) if byte & 0xC0 == 0x80 => Ok((byte & 0x3F) as u16),
+        _ => Err(bad()),
+    };
+    while let Some(&byte) = bytes.next() {
+        let unit = match byte {
+            0x01..=0x7F => byte as u16,
+            0xC0..=0xDF => ((byte & 0x1F) as u16) << 6 | continuation(&mut bytes)?,
+            0xE0..=0xEF => {
+                let high = ((byte & 0x0F) as u16) << 12 | continuation(&mut bytes)? << 6;
+                high | continuation(&mut bytes)?
+            }
+            _ => return Err(bad()),
+        };
+        units.push(unit);
+    }
+    Ok(String::from_utf16(&units)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn everything() -> Nbt {
+        Nbt::compound(vec![
+            ("byte", Nbt::Byte(-1)),
+            ("short", Nbt::Short(300)),
+            ("int", Nbt::Int(i32::MIN)),
+            ("long", Nbt::Long(i64::MAX)),
+            ("float", Nbt::Float(0.5)),
+            ("double", Nbt::Double(-2.25)),
+            ("bytes", Nbt::ByteArray(vec![1, -2, 3])),
+            ("string", Nbt::String("nul \0 and 🎉".to_owned())),
+            ("list", Nbt::List(vec![Nbt::Int(1), Nbt::Int(2)])),
+            ("empty list", Nbt::List(vec![])),
+            (
+                "nested",
+                Nbt::compound(vec![("inner", Nbt::compound(Vec::<(String, _)>::new()))]),
+            ),
+            ("ints", Nbt::IntArray(vec![7, -7])),
+            ("longs", Nbt::LongArray(vec![1 << 40])),
+        ])
+    }
+
+    #[test]
+    fn test_named_round_trip() -> Result<(), Error> {
+        let mut buf = vec![];
+        write_named("root", &everything(), &mut buf)?;
+        assert_eq!(&[COMPOUND, 0x00, 0x04], &buf[..3]);
+        let (name, value) = read_named(&mut &buf[..], Limits::default())?;
+        assert_eq!("root", name);
+        assert_eq!(everything(), value);
+        Ok(())
+    }
+
+    #[test]
+    fn test_network_round_trip() -> Result<(), Error> {
+        let mut buf = vec![];
+        write_network(Some(&everything()), &mut buf)?;
+        // No name, straight into the first entry
+        assert_eq!(&[COMPOUND, BYTE, 0x00, 0x04], &buf[..4]);
+        assert_eq!(
+            Some(everything()),
+            read_network(&mut &buf[..], Limits::default())?
+        );
+        // Chat components can be a bare string
+        let mut buf = vec![];
+        Nbt::String("hi".to_owned()).encode(&mut buf)?;
+        assert_eq!(vec![STRING, 0x00, 0x02, b'h', b'i'], buf);
+        assert_eq!(Nbt::String("hi".to_owned()), Nbt::decode(&mut &buf[..])?);
+        assert_eq!(None, read_network(&mut &[END][..], Limits::default())?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_modified_utf8() -> Result<(), Error> {
+        assert_eq!(vec![0xC0, 0x80], encode_mutf8("\0"));
+        // Outside the BMP it's two 3 byte surrogates, not the usual 4 bytes
+        let party = encode_mutf8("🎉");
+        assert_eq!(vec![0xED, 0xA0, 0xBC, 0xED, 0xBE, 0x89], party);
+        assert_eq!("🎉", decode_mutf8(&party)?);
+        assert!(decode_mutf8(&[0x00]).is_err());
+        assert!(decode_mutf8(&[0xC0]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_limits() -> Result<(), Error> {
+        let mut buf = vec![];
+        write_network(Some(&everything()), &mut buf)?;
+        let tight = Limits {
+            max_bytes: buf.len() - 1,
+            ..Limits::default()
+        };
+        assert!(read_network(&mut &buf[..], tight).is_err());
+
+        // A byte array claiming to be 2GB shouldn't get allocated
+        let mut huge = vec![BYTE_ARRAY];
+        huge.extend_from_slice(&i32::MAX.to_be_bytes());
+        assert!(read_network(&mut &huge[..], Limits::default()).is_err());
+        // Nor should lists of lists each claiming to be 2M long
+        let mut nested = vec![LIST];
+        for _ in 0..500 {
+            nested.push(LIST);
+            nested.extend_from_slice(&(1_i32 << 21).to_be_bytes());
+        }
+        assert!(read_network(&mut &nested[..], Limits::default()).is_err());
+
+        let mut deep = Nbt::Int(0);
+        for _ in 0..10 {
+            deep = Nbt::List(vec![deep]);
+        }
+        let mut buf = vec![];
+        write_network(Some(&deep), &mut buf)?;
+        let shallow = Limits {
+            max_depth: 9,
+            ..Limits::default()
+        };
+        assert!(read_network(&mut &buf[..], shallow).is_err());
+        let just_enough = Limits {
+            max_depth: 10,
+            ..Limits::default()
+        };
+        assert_eq!(Some(deep), read_network(&mut &buf[..], just_enough)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_bad_nbt() {
+        let mixed = Nbt::List(vec![Nbt::Int(1), Nbt::Byte(1)]);
+        assert!(write_network(Some(&mixed), &mut vec![]).is_err());
+        assert!(read_network(&mut &[13][..], Limits::default()).is_err());
+        let negative = [INT_ARRAY, 0xff, 0xff, 0xff, 0xff];
+        assert!(read_network(&mut &negative[..], Limits::default()).is_err());
+    }
+
+    // Packets can have NBT fields like anything else
+    packet! {
+        pub struct WithNbt = 0x00 {
+            pub data: Nbt,
+        }
+    }
+
+    #[test]
+    fn test_nbt_in_packet() -> Result<(), Error> {
+        let packet = WithNbt { data: everything() };
+        let mut buf = vec![];
+        packet.encode(&mut buf)?;
+        assert_eq!(packet, WithNbt::decode(&mut &buf[..])?);
+        Ok(())
+    }
+}
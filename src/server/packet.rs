@@ -0,0 +1,279 @@
+/*
+    Every packet the facade knows, grouped by the state they're sent in. Each one can be written
+    and read back (see codec::packet!), so the same definitions work whether the facade is
+    playing the server or the client.
+*/
+
+use super::chat::Component;
+use super::codec::{ByteArray, Decode, Rest, Trailing, VarInt};
+use super::read::packet::ConnectionState;
+use crate::error::Error;
+
+// Handshaking
+
+packet! {
+    pub struct Handshake = 0x00 {
+        pub protocol_version: i32 as VarInt,
+        pub server_address: String,
+        pub server_port: u16,
+        pub next_state: i32 as VarInt,
+    }
+}
+
+impl Handshake {
+    /// The state the connection moves to after this handshake. 3 is a transfer from another
+    /// server (1.20.5+), which is a login as far as we're concerned.
+    pub fn next_state(&self) -> Result<ConnectionState, Error> {
+        match self.next_state {
+            1 => Ok(ConnectionState::Status),
+            2 | 3 => Ok(ConnectionState::Login),
+            other => Err(Error::protocol(format!(
+                "Handshake asked for unknown state {}",
+                other
+            ))),
+        }
+    }
+}
+
+// Status
+
+packet! {
+    /// Sent after the handshake to ask for the server list status
+    pub struct StatusRequest = 0x00 {}
+}
+
+packet! {
+    /// The server list status as json, see status::ServerStatus
+    pub struct StatusResponse = 0x00 {
+        pub json: String,
+    }
+}
+
+packet! {
+    pub struct PingRequest = 0x01 {
+        pub payload: i64,
+    }
+}
+
+packet! {
+    /// Echoes the ping's payload back
+    pub struct PongResponse = 0x01 {
+        pub payload: i64,
+    }
+}
+
+// Login
+
+packet! {
+    /// The first packet of a login
+    pub struct LoginStart = 0x00 {
+        pub name: String,
+        /// Newer clients send their UUID (and for a while, a signing key) after the name, but
+        /// what's there depends on the protocol version. See uuid().
+        pub rest: Vec<u8> as Rest,
+    }
+}
+
+impl LoginStart {
+    /// The player's UUID, if a client speaking `protocol` sends it
+    pub fn uuid(&self, protocol: i32) -> Option<u128> {
+        read_uuid(&mut &self.rest[..], protocol).unwrap_or_else(|e| {
+            debug!("Couldn't read the UUID in a login start: {}", e);
+            None
+        })
+    }
+}
+
+fn read_uuid(rest: &mut &[u8], protocol: i32) -> Result<Option<u128>, Error> {
+    match protocol {
+        // 1.19.1 and 1.19.2: an optional signing key (expiry, key, signature) then an
+        // optional UUID
+        760 => {
+            if bool::decode(rest)? {
+                i64::decode(rest)?;
+                ByteArray::decode(rest)?;
+                ByteArray::decode(rest)?;
+            }
+            Option::<u128>::decode(rest)
+        }
+        // 1.19.3 - 1.20.1: just the optional UUID
+        761..=763 => Option::<u128>::decode(rest),
+        // 1.20.2 onwards: always a UUID
+        p if p >= 764 => Ok(Some(u128::decode(rest)?)),
+        _ => Ok(None),
+    }
+}
+
+packet! {
+    /// Starts online mode encryption, see server::encryption
+    pub struct EncryptionRequest = 0x01 {
+        /// Always empty since 1.7
+        pub server_id: String,
+        /// DER encoded
+        pub public_key: Vec<u8> as ByteArray,
+        pub verify_token: Vec<u8> as ByteArray,
+        /// Whether the client should check in with the session server, added in 1.20.5
+        pub should_authenticate: Option<bool> as Trailing<bool>,
+    }
+}
+
+packet! {
+    /// Both encrypted with the server's public key. 1.19 - 1.19.2 clients can send a signature
+    /// instead of the verify token, which isn't supported.
+    pub struct EncryptionResponse = 0x01 {
+        pub shared_secret: Vec<u8> as ByteArray,
+        pub verify_token: Vec<u8> as ByteArray,
+    }
+}
+
+packet! {
+    /// Everything after this is framed differently, see framing::Framing
+    #[allow(dead_code)]
+    pub struct SetCompression = 0x03 {
+        /// Packets this big or bigger get compressed, negative turns compression off
+        pub threshold: i32 as VarInt,
+    }
+}
+
+packet! {
+    /// A server asking something of the client during login (1.13+), like Velocity asking a
+    /// proxy who is logging in
+    pub struct LoginPluginRequest = 0x04 {
+        pub message_id: i32 as VarInt,
+        pub channel: String,
+        pub data: Vec<u8> as Rest,
+    }
+}
+
+packet! {
+    pub struct LoginPluginResponse = 0x02 {
+        pub message_id: i32 as VarInt,
+        /// Clients that don't know the channel say so, and send no data
+        pub understood: bool,
+        pub data: Vec<u8> as Rest,
+    }
+}
+
+packet! {
+    pub struct LoginDisconnect = 0x00 {
+        /// A chat component as json
+        pub reason: String,
+    }
+}
+
+impl LoginDisconnect {
+    pub fn new(reason: &Component) -> Self {
+        LoginDisconnect {
+            reason: reason.to_json(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::codec::Packet;
+    use std::fmt::Debug;
+
+    fn round_trip<P: Packet + Debug + PartialEq>(packet: P) -> Result<Vec<u8>, Error> {
+        let mut buf = vec![];
+        packet.encode(&mut buf)?;
+        assert_eq!(packet, P::decode(&mut &buf[..])?);
+        Ok(buf)
+    }
+
+    #[test]
+    fn test_round_trips() -> Result<(), Error> {
+        let handshake = round_trip(Handshake {
+            protocol_version: 736,
+            server_address: "localhost".to_owned(),
+            server_port: 8080,
+            next_state: 1,
+        })?;
+        assert_eq!(
+            vec![
+                0xe0, 0x05, 0x09, 0x6c, 0x6f, 0x63, 0x61, 0x6c, 0x68, 0x6f, 0x73, 0x74, 0x1f, 0x90,
+                0x01
+            ],
+            handshake
+        );
+        assert!(round_trip(StatusRequest {})?.is_empty());
+        round_trip(StatusResponse {
+            json: r#"{"description":"hi"}"#.to_owned(),
+        })?;
+        assert_eq!(
+            123_i64.to_be_bytes().to_vec(),
+            round_trip(PingRequest { payload: 123 })?
+        );
+        round_trip(PongResponse { payload: -1 })?;
+        round_trip(LoginStart {
+            name: "bob".to_owned(),
+            rest: vec![1; 16],
+        })?;
+        round_trip(EncryptionRequest {
+            server_id: "".to_owned(),
+            public_key: vec![1, 2, 3],
+            verify_token: vec![4, 5, 6, 7],
+            should_authenticate: Some(true),
+        })?;
+        let old_request = round_trip(EncryptionRequest {
+            server_id: "".to_owned(),
+            public_key: vec![1, 2, 3],
+            verify_token: vec![4, 5, 6, 7],
+            should_authenticate: None,
+        })?;
+        assert_eq!(vec![0, 3, 1, 2, 3, 4, 4, 5, 6, 7], old_request);
+        round_trip(EncryptionResponse {
+            shared_secret: vec![8; 128],
+            verify_token: vec![9; 128],
+        })?;
+        round_trip(SetCompression { threshold: 256 })?;
+        round_trip(LoginPluginRequest {
+            message_id: 7,
+            channel: "velocity:player_info".to_owned(),
+            data: vec![4],
+        })?;
+        let response = round_trip(LoginPluginResponse {
+            message_id: 7,
+            understood: false,
+            data: vec![],
+        })?;
+        assert_eq!(vec![7, 0], response);
+        round_trip(LoginDisconnect::new(&Component::text("Go away")))?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_login_start_uuid() {
+        let uuid = 0x0123_4567_89ab_cdef_0123_4567_89ab_cdef_u128;
+        let login = |rest: Vec<u8>| LoginStart {
+            name: "bob".to_owned(),
+            rest,
+        };
+        assert_eq!(None, login(vec![]).uuid(754));
+        assert_eq!(Some(uuid), login(uuid.to_be_bytes().to_vec()).uuid(765));
+        let mut optional = vec![1];
+        optional.extend_from_slice(&uuid.to_be_bytes());
+        assert_eq!(Some(uuid), login(optional.clone()).uuid(761));
+        assert_eq!(None, login(vec![0]).uuid(763));
+        // No signing key, then the UUID
+        let mut with_key = vec![0];
+        with_key.extend(optional);
+        assert_eq!(Some(uuid), login(with_key).uuid(760));
+        // Garbage is just no UUID
+        assert_eq!(None, login(vec![1, 2]).uuid(765));
+    }
+
+    #[test]
+    fn test_next_state() {
+        let mut handshake = Handshake {
+            protocol_version: 754,
+            server_address: "localhost".to_owned(),
+            server_port: 25565,
+            next_state: 3,
+        };
+        assert_eq!(ConnectionState::Login, handshake.next_state().unwrap());
+        handshake.next_state = 4;
+        assert!(handshake.next_state().is_err());
+    }
+}
@@ -1,5 +1,7 @@
 use crate::error::Error;
-use std::{io::Read, str, u16};
+use crate::server::codec::{Identifier, Position};
+use std::convert::TryInto;
+use std::io::Read;
 use tokio::io::AsyncReadExt;
 /*
  * By "atom", I mean an individual part of a minecraft packet, such as an int, varint, or string.
@@ -15,13 +17,13 @@ pub async fn read_varint_async<S: AsyncReadExt + Unpin>(source: &mut S) -> Resul
     let mut result: i32 = 0;
     let mut buf = [0; 1]; // 1 byte at a time
     loop {
-        let _bytes_read = source.read_exact(&mut buf).await?;
+        source.read_exact(&mut buf).await?;
         let byte = buf[0];
         let value = (byte & 0b01111111) as i32;
         result |= value << (7 * num_read);
         num_read += 1;
         if num_read > 5 {
-            return Err("VarInt is too big".into());
+            return Err(Error::framing("VarInt is too big"));
         }
         if byte & 0b10000000 == 0 {
             break;
@@ -35,13 +37,13 @@ pub fn read_varint(source: &mut impl Read) -> Result<i32, Error> {
     let mut result: i32 = 0;
     let mut buf = [0; 1]; // 1 byte at a time
     loop {
-        let _bytes_read = source.read_exact(&mut buf)?;
+        source.read_exact(&mut buf)?;
         let byte = buf[0];
         let value = (byte & 0b01111111) as i32;
         result |= value << (7 * num_read);
         num_read += 1;
         if num_read > 5 {
-            return Err("VarInt is too big".into());
+            return Err(Error::framing("VarInt is too big"));
         }
         if byte & 0b10000000 == 0 {
             break;
@@ -83,41 +85,202 @@ fn test_read_varint() -> Result<(), Error> {
 }
 
 pub fn read_string(source: &mut impl Read) -> Result<String, Error> {
-    let size = read_varint(source)? as usize;
-    let mut buf: Vec<u8> = vec![0; size];
-    source.read_exact(&mut buf)?;
-    Ok(str::from_utf8(&buf)?.to_owned())
+    let length = read_length(source)?;
+    let mut buf = vec![];
+    // Like byte arrays, only as much as is really there
+    source.take(length as u64).read_to_end(&mut buf)?;
+    if buf.len() != length {
+        return Err(Error::framing("String is shorter than its length"));
+    }
+    Ok(String::from_utf8(buf)?)
 }
 
 #[test]
 fn test_read_string() -> Result<(), Error> {
     let mut buf: &[u8] = &[0x02, 0x48, 0x49]; // Varint<2>, Utf8<H>, Utf8<I>
     assert_eq!("HI", read_string(&mut buf)?);
+    // -1, and a length with nothing behind it
+    let mut buf: &[u8] = &[0xff, 0xff, 0xff, 0xff, 0x0f];
+    assert!(matches!(read_string(&mut buf), Err(Error::Framing(_))));
+    let mut buf: &[u8] = &[0xff, 0xff, 0xff, 0xff, 0x07, 0x48];
+    assert!(matches!(read_string(&mut buf), Err(Error::Framing(_))));
     Ok(())
 }
 
 pub fn read_u16(source: &mut impl Read) -> Result<u16, Error> {
     let mut buf = [0; 2];
-    source.read(&mut buf)?;
+    source.read_exact(&mut buf)?;
     Ok(u16::from_be_bytes(buf))
 }
 
 #[test]
 fn test_read_u16() -> Result<(), Error> {
-    let mut buf: &[u8] = &(1 as u16).to_be_bytes();
+    let mut buf: &[u8] = &1_u16.to_be_bytes();
     assert_eq!(1, read_u16(&mut buf)?);
     Ok(())
 }
 
 pub fn read_i64(source: &mut impl Read) -> Result<i64, Error> {
     let mut buf = [0; 8];
-    source.read(&mut buf)?;
+    source.read_exact(&mut buf)?;
     Ok(i64::from_be_bytes(buf))
 }
 
 #[test]
 fn test_read_i64() -> Result<(), Error> {
-    let mut buf: &[u8] = &(-1 as i64).to_be_bytes();
+    let mut buf: &[u8] = &(-1_i64).to_be_bytes();
     assert_eq!(-1, read_i64(&mut buf)?);
     Ok(())
 }
+
+pub fn read_varlong(source: &mut impl Read) -> Result<i64, Error> {
+    let mut num_read: u64 = 0;
+    let mut result: i64 = 0;
+    let mut buf = [0; 1];
+    loop {
+        source.read_exact(&mut buf)?;
+        if num_read == 10 {
+            return Err(Error::framing("VarLong is too big"));
+        }
+        let byte = buf[0];
+        let value = (byte & 0b01111111) as i64;
+        result |= value << (7 * num_read);
+        num_read += 1;
+        if byte & 0b10000000 == 0 {
+            break;
+        }
+    }
+    Ok(result)
+}
+
+#[test]
+fn test_read_varlong() -> Result<(), Error> {
+    let mut buf: &[u8] = &[0xff, 0x01];
+    assert_eq!(255, read_varlong(&mut buf)?);
+    let mut buf: &[u8] = &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01];
+    assert_eq!(-1, read_varlong(&mut buf)?);
+    let mut buf: &[u8] = &[0xff; 11];
+    assert!(read_varlong(&mut buf).is_err());
+    Ok(())
+}
+
+pub fn read_bool(source: &mut impl Read) -> Result<bool, Error> {
+    match read_u8(source)? {
+        0 => Ok(false),
+        1 => Ok(true),
+        other => Err(Error::framing(format!("{:#x} isn't a bool", other))),
+    }
+}
+
+pub fn read_u8(source: &mut impl Read) -> Result<u8, Error> {
+    let mut buf = [0; 1];
+    source.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+pub fn read_i8(source: &mut impl Read) -> Result<i8, Error> {
+    Ok(read_u8(source)? as i8)
+}
+
+pub fn read_i16(source: &mut impl Read) -> Result<i16, Error> {
+    let mut buf = [0; 2];
+    source.read_exact(&mut buf)?;
+    Ok(i16::from_be_bytes(buf))
+}
+
+pub fn read_i32(source: &mut impl Read) -> Result<i32, Error> {
+    let mut buf = [0; 4];
+    source.read_exact(&mut buf)?;
+    Ok(i32::from_be_bytes(buf))
+}
+
+pub fn read_f32(source: &mut impl Read) -> Result<f32, Error> {
+    let mut buf = [0; 4];
+    source.read_exact(&mut buf)?;
+    Ok(f32::from_be_bytes(buf))
+}
+
+pub fn read_f64(source: &mut impl Read) -> Result<f64, Error> {
+    let mut buf = [0; 8];
+    source.read_exact(&mut buf)?;
+    Ok(f64::from_be_bytes(buf))
+}
+
+#[test]
+fn test_read_bool() -> Result<(), Error> {
+    let mut buf: &[u8] = &[0x00, 0x01, 0x02];
+    assert!(!read_bool(&mut buf)?);
+    assert!(read_bool(&mut buf)?);
+    assert!(read_bool(&mut buf).is_err());
+    Ok(())
+}
+
+/// A UUID as one big-endian 128 bit number
+pub fn read_uuid(source: &mut impl Read) -> Result<u128, Error> {
+    let mut buf = [0; 16];
+    source.read_exact(&mut buf)?;
+    Ok(u128::from_be_bytes(buf))
+}
+
+#[allow(dead_code)]
+pub fn read_position(source: &mut impl Read) -> Result<Position, Error> {
+    let packed = read_i64(source)?;
+    // Shifting the field to the top first makes the shift back down sign extend it
+    Ok(Position {
+        x: (packed >> 38) as i32,
+        y: (packed << 52 >> 52) as i32,
+        z: (packed << 26 >> 38) as i32,
+    })
+}
+
+#[test]
+#[allow(clippy::unusual_byte_groupings)] // Grouped by field
+fn test_read_position() -> Result<(), Error> {
+    // The example from the protocol docs
+    let mut buf: &[u8] =
+        &0b01000110000001110110001100_10110000010101101101001000_001100111111_u64.to_be_bytes();
+    assert_eq!(
+        Position {
+            x: 18357644,
+            y: 831,
+            z: -20882616
+        },
+        read_position(&mut buf)?
+    );
+    Ok(())
+}
+
+#[allow(dead_code)]
+pub fn read_identifier(source: &mut impl Read) -> Result<Identifier, Error> {
+    read_string(source)?.parse()
+}
+
+/// A VarInt length followed by that many bytes
+pub fn read_byte_array(source: &mut impl Read) -> Result<Vec<u8>, Error> {
+    let length = read_length(source)?;
+    let mut buf = vec![];
+    // Only allocate as much as actually arrives, a bad length shouldn't be able to ask for gigabytes
+    source.take(length as u64).read_to_end(&mut buf)?;
+    if buf.len() != length {
+        return Err(Error::framing("Byte array is shorter than its length"));
+    }
+    Ok(buf)
+}
+
+/// The VarInt length in front of strings and arrays
+pub fn read_length(source: &mut impl Read) -> Result<usize, Error> {
+    Ok(read_varint(source)?.try_into()?)
+}
+
+#[test]
+fn test_read_byte_array() -> Result<(), Error> {
+    let mut buf: &[u8] = &[0x02, 0xca, 0xfe];
+    assert_eq!(vec![0xca, 0xfe], read_byte_array(&mut buf)?);
+    let mut buf: &[u8] = &[0xff, 0xff, 0xff, 0xff, 0x07, 0x00];
+    assert!(read_byte_array(&mut buf).is_err());
+    let mut buf: &[u8] = &[0x7f];
+    assert!(matches!(read_length(&mut buf), Ok(127)));
+    let mut buf: &[u8] = &[0xff, 0xff, 0xff, 0xff, 0x0f];
+    assert!(read_length(&mut buf).is_err());
+    Ok(())
+}
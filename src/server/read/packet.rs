@@ -1,36 +1,63 @@
-use super::atom;
 use crate::error::Error;
+use crate::server::codec::{Decode, Packet};
+use crate::server::framing::Framing;
+use crate::server::packet::{Handshake, LoginStart, PingRequest, StatusRequest};
 
-use std::io::{Cursor, Read};
+use std::io::Cursor;
 
 use tokio::io::AsyncReadExt;
 
-#[derive(Debug, Eq, PartialEq)]
-pub enum Packet {
+/// Which set of packets the client can send next. Every connection starts out Handshaking, and
+/// the handshake says whether it moves on to Status or Login.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ConnectionState {
+    Handshaking,
+    Status,
+    Login,
+    // The facade hands logins over to the real server before they get this far
+    #[allow(dead_code)]
+    Play,
+}
+
+/// Packets a client can send us
+#[derive(Debug, PartialEq)]
+pub enum Serverbound {
     Handshake(Handshake),
-    HandshakeRequest(HandshakeRequest),
-    Ping(Ping),
+    StatusRequest(StatusRequest),
+    PingRequest(PingRequest),
+    LoginStart(LoginStart),
 }
 
-pub async fn read<S: AsyncReadExt + Unpin>(source: &mut S) -> Result<Packet, Error> {
-    let length = atom::read_varint_async(source).await? as usize;
-    let mut buf = vec![0; length];
-    if length > 0 {
-        source.read_exact(&mut buf).await?;
-    }
-    let mut cursor = Cursor::new(buf);
+/// Read one packet, decoded according to what `state` the connection is in. Packet ids are only
+/// unique within a state, so there's no way to tell what a packet is without knowing it.
+// Clients are read with a Framing that has their limits, but this is handy for trusted bytes
+#[allow(dead_code)]
+pub async fn read<S: AsyncReadExt + Unpin>(
+    source: &mut S,
+    state: ConnectionState,
+) -> Result<Serverbound, Error> {
+    Framing::default().read(source, state).await
+}
+
+/// Read one packet that has to be a `P`, for when there's only one thing the other side can say
+/// next (like the answers to our own requests in client::ping)
+pub async fn read_packet<P: Packet, S: AsyncReadExt + Unpin>(source: &mut S) -> Result<P, Error> {
+    Framing::default().read_packet(source).await
+}
 
-    let packet_id = atom::read_varint(&mut cursor)?;
-    trace!("reading packet type {:#}", packet_id);
-    match packet_id {
-        Handshake::ID => match length {
-            // Empty packet with 1-byte packet id has length 1 (for the packet id)
-            1 => Ok(Packet::HandshakeRequest(HandshakeRequest {})),
-            _ => Ok(Packet::Handshake(Handshake::decode(&mut cursor)?)),
-        },
-        Ping::ID => Ok(Packet::Ping(Ping::decode(&mut cursor)?)),
-        id => Err(format!("Unknown packet id {}", id).into()),
-    }
+pub(crate) fn decode(
+    state: ConnectionState,
+    packet_id: i32,
+    cursor: &mut Cursor<Vec<u8>>,
+) -> Result<Serverbound, Error> {
+    use ConnectionState::*;
+    Ok(match (state, packet_id) {
+        (Handshaking, Handshake::ID) => Serverbound::Handshake(Handshake::decode(cursor)?),
+        (Status, StatusRequest::ID) => Serverbound::StatusRequest(StatusRequest::decode(cursor)?),
+        (Status, PingRequest::ID) => Serverbound::PingRequest(PingRequest::decode(cursor)?),
+        (Login, LoginStart::ID) => Serverbound::LoginStart(LoginStart::decode(cursor)?),
+        (_, id) => return Err(Error::UnknownPacket(id)),
+    })
 }
 
 #[cfg(test)]
@@ -49,68 +76,77 @@ async fn test_read_handshake() -> AsyncTestResult {
         server_port: 8080,
         next_state: 1,
     };
-    assert_eq!(Packet::Handshake(expected), read(&mut cursor).await?);
+    assert_eq!(
+        Serverbound::Handshake(expected),
+        read(&mut cursor, ConnectionState::Handshaking).await?
+    );
     Ok(())
 }
 
 #[tokio::test]
-async fn test_read_handshake_request() -> AsyncTestResult {
+async fn test_read_status_request() -> AsyncTestResult {
     let mut buf: Vec<u8> = vec![0x01, 0x00];
     let mut cursor = Cursor::new(&mut buf);
     assert_eq!(
-        Packet::HandshakeRequest(HandshakeRequest {}),
-        read(&mut cursor).await?
+        Serverbound::StatusRequest(StatusRequest {}),
+        read(&mut cursor, ConnectionState::Status).await?
     );
+    // The same bytes are the start of a handshake before the state changes
+    let mut cursor = Cursor::new(vec![0x01, 0x00]);
+    assert!(read(&mut cursor, ConnectionState::Handshaking)
+        .await
+        .is_err());
     Ok(())
 }
 
 #[tokio::test]
 async fn test_read_ping() -> AsyncTestResult {
     let mut buf: Vec<u8> = vec![0x09, 0x01];
-    buf.extend_from_slice(&((123 as i64).to_be_bytes()));
+    buf.extend_from_slice(&(123_i64.to_be_bytes()));
     let mut cursor = Cursor::new(&mut buf);
     assert_eq!(
-        Packet::Ping(Ping { payload: 123 }),
-        read(&mut cursor).await?
+        Serverbound::PingRequest(PingRequest { payload: 123 }),
+        read(&mut cursor, ConnectionState::Status).await?
     );
     Ok(())
 }
 
-#[derive(Debug, Eq, PartialEq)]
-pub struct Handshake {
-    pub protocol_version: i32,
-    pub server_address: String,
-    pub server_port: u16,
-    pub next_state: i32,
-}
-
-impl Handshake {
-    const ID: i32 = 0x00;
-    fn decode(source: &mut impl Read) -> Result<Self, Error> {
-        Ok(Handshake {
-            protocol_version: atom::read_varint(source)?,
-            server_address: atom::read_string(source)?,
-            server_port: atom::read_u16(source)?,
-            next_state: atom::read_varint(source)?,
-        })
-    }
+#[tokio::test]
+async fn test_read_login_start() -> AsyncTestResult {
+    let mut cursor = Cursor::new(vec![0x05, 0x00, 0x03, b'b', b'o', b'b']);
+    assert_eq!(
+        Serverbound::LoginStart(LoginStart {
+            name: "bob".to_owned(),
+            rest: vec![]
+        }),
+        read(&mut cursor, ConnectionState::Login).await?
+    );
+    Ok(())
 }
 
-// Minecraft sends a 0x00 empty request after the hanshake packet
-#[derive(Debug, Eq, PartialEq)]
-pub struct HandshakeRequest {}
-
-#[derive(Debug, Eq, PartialEq)]
-pub struct Ping {
-    pub payload: i64,
+#[tokio::test]
+async fn test_read_unknown_packet() {
+    let mut cursor = Cursor::new(vec![0x01, 0x42]);
+    assert!(matches!(
+        read(&mut cursor, ConnectionState::Status).await,
+        Err(Error::UnknownPacket(0x42))
+    ));
+    // Nothing from play is understood
+    let mut cursor = Cursor::new(vec![0x01, 0x00]);
+    assert!(matches!(
+        read(&mut cursor, ConnectionState::Play).await,
+        Err(Error::UnknownPacket(0x00))
+    ));
 }
 
-impl Ping {
-    const ID: i32 = 0x01;
-
-    fn decode(source: &mut impl Read) -> Result<Self, Error> {
-        Ok(Ping {
-            payload: atom::read_i64(source)?,
-        })
-    }
+#[tokio::test]
+async fn test_read_packet() -> AsyncTestResult {
+    use crate::server::packet::PongResponse;
+    let mut buf: Vec<u8> = vec![0x09, 0x01];
+    buf.extend_from_slice(&(-5_i64).to_be_bytes());
+    let pong: PongResponse = read_packet(&mut Cursor::new(buf)).await?;
+    assert_eq!(PongResponse { payload: -5 }, pong);
+    let wrong: Result<PongResponse, _> = read_packet(&mut Cursor::new(vec![0x01, 0x00])).await;
+    assert!(matches!(wrong, Err(Error::UnknownPacket(0x00))));
+    Ok(())
 }
@@ -0,0 +1,212 @@
+/*
+    Asking Mojang's session server whether someone who got through the encryption handshake
+    really is who they said. Before sending its Encryption Response a real client tells the session
+    server it's joining with the server hash, so by the time we ask it has. An impostor can do the
+    handshake with any name, but can't do that without the account.
+    It's one HTTPS GET, so it's done by hand over rustls rather than with a whole HTTP client.
+*/
+
+use crate::error::Error;
+use serde::Deserialize;
+use std::convert::TryFrom;
+use std::io::ErrorKind;
+use std::str;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+const HOST: &str = "sessionserver.mojang.com";
+// The client is waiting on us for all of it
+const SESSION_TIMEOUT: Duration = Duration::from_secs(10);
+// A profile with a skin and cape is a couple of kilobytes
+const MAX_RESPONSE: u64 = 64 * 1024;
+
+/// Who the session server says someone is
+#[derive(Debug, PartialEq)]
+pub struct Profile {
+    pub name: String,
+    pub uuid: u128,
+}
+
+#[derive(Deserialize)]
+struct RawProfile {
+    id: String,
+    name: String,
+}
+
+/// Ask the session server whether `name` joined with `server_hash`, and who they are if they did
+pub async fn has_joined(name: &str, server_hash: &str) -> Result<Profile, Error> {
+    let lookup = async {
+        let socket = TcpStream::connect((HOST, 443)).await?;
+        let host = ServerName::try_from(HOST).unwrap();
+        let stream = connector().connect(host, socket).await?;
+        get(stream, &has_joined_path(name, server_hash)).await
+    };
+    let (status, body) = match timeout(SESSION_TIMEOUT, lookup).await {
+        Ok(result) => result?,
+        Err(_) => return Err(Error::Timeout("waiting for the session server".to_owned())),
+    };
+    match status {
+        200 => parse_profile(&body),
+        204 => Err(Error::protocol(format!(
+            "{} hasn't joined through the session server",
+            name
+        ))),
+        other => Err(Error::protocol(format!(
+            "The session server answered {}",
+            other
+        ))),
+    }
+}
+
+fn connector() -> TlsConnector {
+    let roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(config))
+}
+
+fn has_joined_path(name: &str, server_hash: &str) -> String {
+    format!(
+        "/session/minecraft/hasJoined?username={}&serverId={}",
+        encode(name),
+        encode(server_hash)
+    )
+}
+
+// The name is whatever the client sent, so anything that isn't part of a real name or hash gets
+// percent-encoded
+fn encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'_' | b'-' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+// HTTP/1.0, so the body comes back whole rather than chunked and the server hangs up after it
+async fn get<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    path: &str,
+) -> Result<(u16, Vec<u8>), Error> {
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: facade\r\n\r\n",
+        path, HOST
+    );
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = vec![];
+    match (&mut stream)
+        .take(MAX_RESPONSE)
+        .read_to_end(&mut response)
+        .await
+    {
+        Ok(_) => (),
+        // Plenty of servers hang up without a TLS close_notify, which is fine once they've answered
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof && !response.is_empty() => (),
+        Err(e) => return Err(e.into()),
+    }
+    parse_response(&response)
+}
+
+// The status code and body
+fn parse_response(response: &[u8]) -> Result<(u16, Vec<u8>), Error> {
+    let end = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or_else(|| Error::protocol("The session server's answer was cut off"))?;
+    let head = str::from_utf8(&response[..end])?;
+    let status = head
+        .split(' ')
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| Error::protocol("The session server's answer has no status"))?;
+    Ok((status, response[end + 4..].to_vec()))
+}
+
+fn parse_profile(body: &[u8]) -> Result<Profile, Error> {
+    let raw: RawProfile = serde_json::from_slice(body)?;
+    let uuid = match raw.id.len() {
+        32 => u128::from_str_radix(&raw.id, 16).ok(),
+        _ => None,
+    };
+    let uuid = uuid
+        .ok_or_else(|| Error::protocol(format!("The session server sent a bad UUID {}", raw.id)))?;
+    Ok(Profile {
+        name: raw.name,
+        uuid,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    const NOTCH: u128 = 0x069a79f4_44e9_4726_a5be_fca90e38aaf5;
+
+    #[test]
+    fn test_has_joined_path() {
+        assert_eq!(
+            "/session/minecraft/hasJoined?username=jeb_&serverId=-7c9d5b",
+            has_joined_path("jeb_", "-7c9d5b")
+        );
+        assert_eq!(
+            "/session/minecraft/hasJoined?username=a%26b%20%0D%0A&serverId=1",
+            has_joined_path("a&b \r\n", "1")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get() -> Result<(), Error> {
+        let (client, mut server) = duplex(4096);
+        tokio::spawn(async move {
+            let mut request = [0; 32];
+            server.read_exact(&mut request).await.unwrap();
+            assert!(request.starts_with(b"GET /session/minecraft/hasJoined"));
+            let body =
+                r#"{"id":"069a79f444e94726a5befca90e38aaf5","name":"Notch","properties":[]}"#;
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\r\n{}",
+                body
+            );
+            server.write_all(response.as_bytes()).await.unwrap();
+        });
+        let (status, body) = get(client, &has_joined_path("Notch", "1")).await?;
+        assert_eq!(200, status);
+        assert_eq!(
+            Profile {
+                name: "Notch".to_owned(),
+                uuid: NOTCH
+            },
+            parse_profile(&body)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_response() -> Result<(), Error> {
+        assert_eq!(
+            (204, vec![]),
+            parse_response(b"HTTP/1.1 204 No Content\r\n\r\n")?
+        );
+        assert!(parse_response(b"HTTP/1.1 200 OK\r\nContent-Le").is_err());
+        assert!(parse_profile(br#"{"id":"Notch","name":"Notch"}"#).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_connector() {
+        // Only one crypto provider is built in, so this can't panic picking one
+        connector();
+    }
+}
@@ -0,0 +1,191 @@
+/*
+    Remembers what the real server's status looked like the last time it was up, so while it's
+    asleep the fake server can show the same version, favicon and mod info instead of an obviously
+    fake listing. Only the description and player counts get replaced.
+*/
+
+use crate::error::Error;
+use crate::server::chat::Component;
+use serde_json::{json, Value};
+use std::convert::TryInto;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// A status made up from scratch, for when there's no real one to copy
+#[derive(Debug, Eq, PartialEq)]
+pub struct ServerStatus {
+    pub version_name: String,
+    pub protocol: i32,
+    pub max_players: u32,
+    pub online_players: u32,
+    pub description: Component,
+    /// A data: uri, see server::favicon
+    pub favicon: Option<String>,
+}
+
+impl ServerStatus {
+    pub fn to_json(&self) -> String {
+        let mut json = json!({
+            "version": {
+                "name": self.version_name,
+                "protocol": self.protocol,
+            },
+            "players": {
+                "max": self.max_players,
+                "online": self.online_players,
+                "sample": [],
+            },
+            "description": self.description,
+        });
+        if let Some(favicon) = &self.favicon {
+            json["favicon"] = json!(favicon);
+        }
+        json.to_string()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct StatusCache {
+    last: Mutex<Option<Value>>,
+    // Where to keep a copy, so a restarted facade still has something to show
+    file: Option<PathBuf>,
+}
+
+impl StatusCache {
+    /// A cache that's saved to `file`, starting with whatever was saved there last time
+    pub fn with_file(file: impl Into<PathBuf>) -> Self {
+        let file = file.into();
+        let last = match fs::read_to_string(&file) {
+            Ok(saved) => serde_json::from_str(&saved)
+                .map_err(|e| warn!("Ignoring bad cached status in {}: {}", file.display(), e))
+                .ok(),
+            Err(_) => None,
+        };
+        StatusCache {
+            last: Mutex::new(last),
+            file: Some(file),
+        }
+    }
+
+    /// Remember a status response from the real server
+    pub fn store(&self, json: &str) -> Result<(), Error> {
+        let status: Value = serde_json::from_str(json)?;
+        if !status.is_object() {
+            return Err(Error::protocol("Status response isn't a json object"));
+        }
+        if let Some(file) = &self.file {
+            fs::write(file, json)?;
+        }
+        *self.last.lock().unwrap() = Some(status);
+        Ok(())
+    }
+
+    /// The protocol the real server said it speaks
+    pub fn protocol(&self) -> Option<i32> {
+        let last = self.last.lock().unwrap();
+        let protocol = last.as_ref()?["version"]["protocol"].as_i64()?;
+        protocol.try_into().ok()
+    }
+
+    /// The last status we saw with our own description (and favicon, if we have one), and nobody
+    /// online since the real server isn't up. None if we've never seen the real server.
+    pub fn with_description(
+        &self,
+        description: &Component,
+        favicon: Option<&str>,
+    ) -> Option<String> {
+        let mut status = self.last.lock().unwrap().clone()?;
+        status["description"] = json!(description);
+        if let Some(favicon) = favicon {
+            status["favicon"] = json!(favicon);
+        }
+        if let Some(players) = status.get_mut("players").and_then(Value::as_object_mut) {
+            players.insert("online".to_owned(), json!(0));
+            players.remove("sample");
+        }
+        Some(status.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_server_status_escapes() -> Result<(), Error> {
+        let status = ServerStatus {
+            version_name: r#"1.16 "quoted""#.to_owned(),
+            protocol: 754,
+            max_players: 20,
+            online_players: 0,
+            description: Component::from_legacy(r#"§cC:\ "drive""#),
+            favicon: None,
+        };
+        let json: Value = serde_json::from_str(&status.to_json())?;
+        assert_eq!(r#"1.16 "quoted""#, json["version"]["name"]);
+        assert_eq!(r#"C:\ "drive""#, json["description"]["text"]);
+        assert_eq!("red", json["description"]["color"]);
+        Ok(())
+    }
+
+    const REAL_STATUS: &str = r#"{
+        "version": {"name": "Paper 1.16.5", "protocol": 754},
+        "players": {"max": 20, "online": 2, "sample": [{"name": "Steve", "id": "00000000-0000-0000-0000-000000000000"}]},
+        "description": {"text": "A real server"},
+        "favicon": "data:image/png;base64,AAAA",
+        "modinfo": {"type": "FML", "modList": []}
+    }"#;
+
+    #[test]
+    fn test_with_description() -> Result<(), Error> {
+        let cache = StatusCache::default();
+        assert_eq!(
+            None,
+            cache.with_description(&Component::text("Asleep"), None)
+        );
+        cache.store(REAL_STATUS)?;
+        let status: Value = serde_json::from_str(
+            &cache
+                .with_description(&Component::text("Asleep"), None)
+                .unwrap(),
+        )?;
+        assert_eq!("Asleep", status["description"]["text"]);
+        assert_eq!("Paper 1.16.5", status["version"]["name"]);
+        assert_eq!(0, status["players"]["online"]);
+        assert_eq!(20, status["players"]["max"]);
+        assert!(status["players"].get("sample").is_none());
+        assert_eq!("data:image/png;base64,AAAA", status["favicon"]);
+        assert_eq!("FML", status["modinfo"]["type"]);
+        let status: Value = serde_json::from_str(
+            &cache
+                .with_description(&Component::text("Asleep"), Some("data:zzz"))
+                .unwrap(),
+        )?;
+        assert_eq!("data:zzz", status["favicon"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rejects_garbage() {
+        let cache = StatusCache::default();
+        assert!(cache.store("not json").is_err());
+        assert!(cache.store("[]").is_err());
+        assert_eq!(
+            None,
+            cache.with_description(&Component::text("Asleep"), None)
+        );
+    }
+
+    #[test]
+    fn test_saved_to_file() -> Result<(), Error> {
+        let file = std::env::temp_dir().join(format!("facade-status-test-{}", std::process::id()));
+        StatusCache::with_file(&file).store(REAL_STATUS)?;
+        let reloaded = StatusCache::with_file(&file);
+        let _ = fs::remove_file(&file);
+        assert!(reloaded
+            .with_description(&Component::text("Asleep"), None)
+            .is_some());
+        Ok(())
+    }
+}
@@ -1,6 +1,6 @@
 use crate::error::Error;
+use crate::server::codec::{Identifier, Position};
 use std::convert::TryInto;
-use std::i64;
 use std::io::Write;
 
 pub fn write_varint(value: i32, sink: &mut impl Write) -> Result<(), Error> {
@@ -10,7 +10,7 @@ pub fn write_varint(value: i32, sink: &mut impl Write) -> Result<(), Error> {
     let mut iterations = 0;
     loop {
         let mut temp: u8 = (value & 0b01111111) as u8;
-        value = value >> 7;
+        value >>= 7;
         if value != 0 {
             temp |= 0b10000000;
         }
@@ -19,7 +19,7 @@ pub fn write_varint(value: i32, sink: &mut impl Write) -> Result<(), Error> {
             break;
         }
         if iterations > 6 {
-            return Err("Too many iterations".into());
+            return Err(Error::framing("VarInt is too big"));
         }
         iterations += 1;
     }
@@ -47,3 +47,101 @@ pub fn write_string(value: &str, sink: &mut impl Write) -> Result<(), Error> {
     sink.write_all(value.as_bytes())?;
     Ok(())
 }
+
+pub fn write_u16(value: u16, sink: &mut impl Write) -> Result<(), Error> {
+    Ok(sink.write_all(&value.to_be_bytes())?)
+}
+
+pub fn write_varlong(value: i64, sink: &mut impl Write) -> Result<(), Error> {
+    // Same as write_varint, with room for up to 10 bytes
+    let mut value = value as u64;
+    loop {
+        let mut temp: u8 = (value & 0b01111111) as u8;
+        value >>= 7;
+        if value != 0 {
+            temp |= 0b10000000;
+        }
+        sink.write_all(&[temp])?;
+        if value == 0 {
+            return Ok(());
+        }
+    }
+}
+
+#[test]
+fn test_write_varlong() -> Result<(), Error> {
+    use crate::server::read::atom::read_varlong;
+    for i in [i64::MIN, -1, 0, 1, 255, 1 << 40, i64::MAX].iter() {
+        let mut buf = vec![];
+        write_varlong(*i, &mut buf)?;
+        assert!(buf.len() <= 10);
+        assert_eq!(*i, read_varlong(&mut &buf[..])?);
+    }
+    Ok(())
+}
+
+pub fn write_bool(value: bool, sink: &mut impl Write) -> Result<(), Error> {
+    write_u8(value as u8, sink)
+}
+
+pub fn write_u8(value: u8, sink: &mut impl Write) -> Result<(), Error> {
+    Ok(sink.write_all(&[value])?)
+}
+
+pub fn write_i8(value: i8, sink: &mut impl Write) -> Result<(), Error> {
+    Ok(sink.write_all(&value.to_be_bytes())?)
+}
+
+pub fn write_i16(value: i16, sink: &mut impl Write) -> Result<(), Error> {
+    Ok(sink.write_all(&value.to_be_bytes())?)
+}
+
+pub fn write_i32(value: i32, sink: &mut impl Write) -> Result<(), Error> {
+    Ok(sink.write_all(&value.to_be_bytes())?)
+}
+
+pub fn write_f32(value: f32, sink: &mut impl Write) -> Result<(), Error> {
+    Ok(sink.write_all(&value.to_be_bytes())?)
+}
+
+pub fn write_f64(value: f64, sink: &mut impl Write) -> Result<(), Error> {
+    Ok(sink.write_all(&value.to_be_bytes())?)
+}
+
+pub fn write_uuid(value: u128, sink: &mut impl Write) -> Result<(), Error> {
+    Ok(sink.write_all(&value.to_be_bytes())?)
+}
+
+#[allow(dead_code)]
+pub fn write_position(value: &Position, sink: &mut impl Write) -> Result<(), Error> {
+    let packed = ((value.x as i64 & 0x3FF_FFFF) << 38)
+        | ((value.z as i64 & 0x3FF_FFFF) << 12)
+        | (value.y as i64 & 0xFFF);
+    write_i64(packed, sink)
+}
+
+#[test]
+fn test_write_position() -> Result<(), Error> {
+    use crate::server::read::atom::read_position;
+    let position = Position {
+        x: -33_554_432,
+        y: -64,
+        z: 33_554_431,
+    };
+    let mut buf = vec![];
+    write_position(&position, &mut buf)?;
+    assert_eq!(8, buf.len());
+    assert_eq!(position, read_position(&mut &buf[..])?);
+    Ok(())
+}
+
+#[allow(dead_code)]
+pub fn write_identifier(value: &Identifier, sink: &mut impl Write) -> Result<(), Error> {
+    write_string(&value.to_string(), sink)
+}
+
+pub fn write_byte_array(value: &[u8], sink: &mut impl Write) -> Result<(), Error> {
+    write_varint(value.len().try_into()?, sink)?;
+    sink.write_all(value)?;
+    Ok(())
+}
@@ -1,2 +1,2 @@
-mod atom;
+pub(crate) mod atom;
 pub mod packet;
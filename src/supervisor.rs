@@ -0,0 +1,1088 @@
+/*
+    Lifecycle of the facade:
+        Sleeping: fake server, waiting for someone to log in
+        Starting: the provisioner is booting the real server, the fake server keeps kicking people
+                  (or holds them until it's up, with server.hold_logins)
+        Running: everyone gets proxied to the real server
+        Draining: still proxying, but nobody is online - shut down if it stays that way
+    Whether anyone is online comes from polling `list` over rcon if there's a password configured,
+    otherwise from counting the connections we're proxying.
+        Stopping: the provisioner is shutting the real server down, back to the fake server.
+                  A login now starts it again as soon as it's down.
+    The listener is bound once and owned here for the whole loop, so the port never has to be
+    rebound between the fake server and the proxy.
+*/
+
+use crate::config::{Config, ProxyMode, ServerConfig};
+use crate::error::Error;
+use crate::idle::{Activity, IdleWatcher};
+use crate::provisioner::{self, BackendStatus, Provisioner};
+use crate::proxy::{outbound_header, proxy, replay};
+use crate::proxy_protocol;
+use crate::server::chat::Component;
+use crate::server::client::{ping, ping_within, PING_TIMEOUT};
+use crate::server::encryption::{encrypt_login, ServerKey};
+use crate::server::fake_server::{
+    admit, admit_encrypted, handle_connection, kick, ConnectionResult, Listing,
+};
+use crate::server::favicon::Favicons;
+use crate::server::framing::Framing;
+use crate::server::intercept::{intercept, Intercepted};
+use crate::server::legacy::is_legacy_ping;
+use crate::server::limits::{Limiter, Permit};
+use crate::server::status::StatusCache;
+use crate::util::race::{race, RaceResult};
+use crate::wake::{self, WakePolicy};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch};
+use tokio::time::{sleep, timeout};
+
+// How often to check whether the real server is up (or down) yet
+const BACKEND_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum State {
+    Sleeping,
+    Starting,
+    Running,
+    Draining,
+    Stopping,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Event {
+    LoginAttempt,
+    BackendReady,
+    BackendFailed,
+    PlayerJoined,
+    Empty,
+    IdleTimeout,
+    Stopped,
+}
+
+/// Everything the rest of the facade might want to know about the lifecycle
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Status {
+    pub state: State,
+    /// Where players get proxied to, once the real server is up
+    pub backend_addr: Option<String>,
+    /// When we expect the real server to be up, while it's starting
+    pub ready_at: Option<Instant>,
+}
+
+impl State {
+    /// The state to move to when `event` happens in this state, or None if it doesn't matter here
+    pub fn next(self, event: Event) -> Option<State> {
+        use Event::*;
+        use State::*;
+        match (self, event) {
+            (Sleeping, LoginAttempt) => Some(Starting),
+            // The real server was already up when we started
+            (Sleeping, BackendReady) => Some(Running),
+            (Starting, BackendReady) => Some(Running),
+            (Starting, BackendFailed) => Some(Sleeping),
+            (Running, Empty) => Some(Draining),
+            (Draining, PlayerJoined) => Some(Running),
+            (Draining, IdleTimeout) => Some(Stopping),
+            // The idle watcher can see the server empty out between our own checks
+            (Running, IdleTimeout) => Some(Stopping),
+            (Stopping, Stopped) => Some(Sleeping),
+            _ => None,
+        }
+    }
+}
+
+// What the spawned tasks report back to the supervisor loop
+#[derive(Debug)]
+enum Message {
+    Event(Event),
+    // The real server is answering pings at this address
+    Started(String),
+    // Tagged with the generation it was started in, so a timer from an old Draining state
+    // can't stop the server after players have come and gone again
+    Timeout(u64),
+    // Only connections from players, not server list pings, unless proxy.mode is raw. Then the
+    // IdleWatcher is what decides when it's empty.
+    ConnectionOpened,
+    ConnectionClosed,
+    // The IdleWatcher gave up, so it's back to counting connections
+    WatcherFailed,
+    // A connection whose PROXY header said where it's really from, still counted against the
+    // balancer's address
+    Accepted(TcpStream, SocketAddr, Permit),
+}
+
+pub struct Supervisor {
+    config: Arc<Config>,
+    provisioner: Arc<dyn Provisioner>,
+    listener: TcpListener,
+    status_tx: watch::Sender<Status>,
+    status_rx: watch::Receiver<Status>,
+    messages_tx: mpsc::UnboundedSender<Message>,
+    messages_rx: mpsc::UnboundedReceiver<Message>,
+    generation: u64,
+    active_connections: usize,
+    // Whether an IdleWatcher is deciding when the server is empty, instead of our connection count
+    watching: bool,
+    // How long the last start took, for guessing how long the next one will
+    expected_start: Duration,
+    started_at: Option<Instant>,
+    // The real server's own status, shown while it's asleep if server.passthrough_status is on
+    status_cache: Option<Arc<StatusCache>>,
+    favicons: Arc<Favicons>,
+    // Only in online mode
+    server_key: Option<Arc<ServerKey>>,
+    wake_policy: Arc<dyn WakePolicy>,
+    limiter: Arc<Limiter>,
+    // Someone logged in while it was stopping, so start it again once it's stopped
+    wake_after_stop: bool,
+}
+
+impl Supervisor {
+    pub async fn bind(config: Config) -> Result<Self, Error> {
+        let listener = TcpListener::bind(&config.server.listen).await?;
+        info!("Listening on {}", config.server.listen);
+        Self::new(config, listener)
+    }
+
+    pub fn new(config: Config, listener: TcpListener) -> Result<Self, Error> {
+        let provisioner = provisioner::from_config(&config)?;
+        Self::with_provisioner(config, listener, provisioner)
+    }
+
+    pub fn with_provisioner(
+        config: Config,
+        listener: TcpListener,
+        provisioner: Arc<dyn Provisioner>,
+    ) -> Result<Self, Error> {
+        let (status_tx, status_rx) = watch::channel(Status {
+            state: State::Sleeping,
+            backend_addr: None,
+            ready_at: None,
+        });
+        let (messages_tx, messages_rx) = mpsc::unbounded_channel();
+        let status_cache = match (
+            &config.server.passthrough_status,
+            &config.server.status_cache,
+        ) {
+            (false, _) => None,
+            (true, Some(file)) => Some(Arc::new(StatusCache::with_file(file))),
+            (true, None) => Some(Arc::new(StatusCache::default())),
+        };
+        let server_key = match config.server.online_mode {
+            true => Some(Arc::new(ServerKey::generate()?)),
+            false => None,
+        };
+        Ok(Supervisor {
+            server_key,
+            wake_policy: wake::from_config(&config)?,
+            limiter: Arc::new(Limiter::new(config.limits.clone())),
+            favicons: Arc::new(Favicons::load(&config.server)?),
+            expected_start: config.backend.expected_start(),
+            config: Arc::new(config),
+            provisioner,
+            listener,
+            status_tx,
+            status_rx,
+            messages_tx,
+            messages_rx,
+            generation: 0,
+            active_connections: 0,
+            watching: false,
+            started_at: None,
+            status_cache,
+            wake_after_stop: false,
+        })
+    }
+
+    /// Watch the lifecycle from elsewhere
+    pub fn subscribe(&self) -> watch::Receiver<Status> {
+        self.status_rx.clone()
+    }
+
+    pub fn state(&self) -> State {
+        self.status_rx.borrow().state
+    }
+
+    fn publish(&self, update: impl FnOnce(&mut Status)) {
+        let mut status = self.status_rx.borrow().clone();
+        update(&mut status);
+        // Can't fail, we hold a receiver
+        let _ = self.status_tx.send(status);
+    }
+
+    pub async fn run(mut self) -> Result<(), Error> {
+        self.check_already_running();
+        loop {
+            match race(self.listener.accept(), self.messages_rx.recv()).await {
+                RaceResult::Left(accept_result) => {
+                    let (socket, peer) = accept_result?;
+                    debug!("Got a connection from {} while {:?}", peer, self.state());
+                    match self.config.proxy_protocol.accept {
+                        true => self.read_proxy_header(socket, peer),
+                        false => self.dispatch(socket, peer, None),
+                    }
+                }
+                RaceResult::Right(Some(message)) => self.handle(message),
+                // We hold a sender ourselves, so this can't happen
+                RaceResult::Right(None) => unreachable!("the supervisor holds a sender"),
+            }
+        }
+    }
+
+    // Find out where the connection really came from before deciding what to do with it
+    fn read_proxy_header(&self, mut socket: TcpStream, balancer: SocketAddr) {
+        if !self.config.proxy_protocol.trusts(balancer.ip()) {
+            return debug!(
+                "Closed a connection from {}, which isn't a trusted balancer",
+                balancer
+            );
+        }
+        // Everyone comes from the balancer, so it only counts towards the cap for now
+        let permit = match self.limiter.reserve(balancer.ip()) {
+            Some(permit) => permit,
+            None => return,
+        };
+        let messages = self.messages_tx.clone();
+        tokio::spawn(async move {
+            match proxy_protocol::accept(&mut socket).await {
+                Ok(peer) => {
+                    debug!("Connection is really from {}", peer);
+                    let _ = messages.send(Message::Accepted(socket, peer, permit));
+                }
+                Err(e) => debug!("Closed a connection without a PROXY header: {}", e),
+            }
+        });
+    }
+
+    // If the facade restarts while the real server is up, pick up where we left off
+    fn check_already_running(&self) {
+        let provisioner = self.provisioner.clone();
+        let messages = self.messages_tx.clone();
+        tokio::spawn(async move {
+            match provisioner.status().await {
+                Ok(BackendStatus::Running) => match provisioner.address().await {
+                    Ok(addr) => {
+                        info!("Real server is already running at {}", addr);
+                        let _ = messages.send(Message::Started(addr));
+                    }
+                    Err(e) => error!("Real server is running but has no address: {}", e),
+                },
+                Ok(BackendStatus::Stopped) => (),
+                Err(e) => warn!("Couldn't check whether the real server is running: {}", e),
+            }
+        });
+    }
+
+    // `permit` is for connections that came through a balancer and already count towards the cap
+    fn dispatch(&mut self, socket: TcpStream, peer: SocketAddr, permit: Option<Permit>) {
+        let header = match outbound_header(&self.config.proxy_protocol, &socket, peer) {
+            Ok(header) => header,
+            Err(e) => return error!("{}", e),
+        };
+        let backend_addr = self.status_rx.borrow().backend_addr.clone();
+        match (self.state(), backend_addr) {
+            (State::Running, Some(backend_addr)) | (State::Draining, Some(backend_addr)) => {
+                let messages = self.messages_tx.clone();
+                match self.config.proxy.mode {
+                    ProxyMode::Raw => {
+                        self.handle(Message::ConnectionOpened);
+                        tokio::spawn(async move {
+                            if let Err(e) = proxy(socket, backend_addr, &header).await {
+                                error!("{}", e);
+                            }
+                            let _ = messages.send(Message::ConnectionClosed);
+                        });
+                    }
+                    ProxyMode::Transparent => {
+                        let config = self.config.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = proxy_transparently(
+                                socket,
+                                peer,
+                                backend_addr,
+                                &header,
+                                &config,
+                                messages,
+                            )
+                            .await
+                            {
+                                error!("{}", e);
+                            }
+                        });
+                    }
+                }
+            }
+            _ => {
+                // Players get through to the real server no matter what, but the fake server
+                // has to look after itself
+                let permit = match permit {
+                    Some(permit) => self.limiter.rate_limit(permit, peer.ip()),
+                    None => self.limiter.admit(peer.ip()),
+                };
+                let permit = match permit {
+                    Some(permit) => permit,
+                    None => return,
+                };
+                let limiter = self.limiter.clone();
+                let config = self.config.clone();
+                let messages = self.messages_tx.clone();
+                let status = self.subscribe();
+                let description = describe_status(&config.server, &status.borrow());
+                let sleeping = status.borrow().state == State::Sleeping;
+                // A login while it's stopping starts it again afterwards, so that has to be
+                // allowed too
+                let waking = sleeping || status.borrow().state == State::Stopping;
+                let cache = self.status_cache.clone();
+                let favicons = self.favicons.clone();
+                let protocol = self.backend_protocol();
+                let key = self.server_key.clone();
+                let policy = self.wake_policy.clone();
+                tokio::spawn(async move {
+                    let _permit = permit;
+                    let listing = Listing {
+                        description: &description,
+                        favicon: favicons.pick(sleeping),
+                        cache: cache.as_deref(),
+                        protocol,
+                    };
+                    match handle_connection(socket, peer, &config.server, &listing, &limiter).await
+                    {
+                        Ok(ConnectionResult::Login(login)) => {
+                            let player = login.player().unwrap_or_default().to_owned();
+                            // Once it's on its way up there's nothing left to decide, and online
+                            // mode waits until it knows who they are
+                            let policy_now = (waking && key.is_none()).then(|| &*policy);
+                            let login = match admit(policy_now, login, &config, protocol).await {
+                                Some(login) => login,
+                                None => return,
+                            };
+                            if let Some(key) = key {
+                                let mut encrypted = match encrypt_login(login, &key, &limiter).await
+                                {
+                                    Ok(encrypted) => encrypted,
+                                    Err(e) => {
+                                        info!("Turning away {}, who didn't encrypt: {}", player, e);
+                                        return;
+                                    }
+                                };
+                                if let Err(e) = encrypted.verify().await {
+                                    info!("Turning away {}, who isn't verified: {}", player, e);
+                                    let reason = Component::text("Failed to verify username!");
+                                    if let Err(e) = encrypted.kick(&reason).await {
+                                        error!("{}", e);
+                                    }
+                                    return;
+                                }
+                                // Only now, so the policy sees who they really are
+                                let encrypted = match waking {
+                                    true => {
+                                        match admit_encrypted(&*policy, encrypted, &config).await {
+                                            Some(encrypted) => encrypted,
+                                            None => return,
+                                        }
+                                    }
+                                    false => encrypted,
+                                };
+                                info!(
+                                    "{} logged in and was verified, waking the real server",
+                                    encrypted.name
+                                );
+                                let _ = messages.send(Message::Event(Event::LoginAttempt));
+                                let reason = Component::from_legacy(&config.server.kick_message);
+                                if let Err(e) = encrypted.kick(&reason).await {
+                                    error!("{}", e);
+                                }
+                                return;
+                            }
+                            info!("{} logged in, waking the real server", player);
+                            let _ = messages.send(Message::Event(Event::LoginAttempt));
+                            if let Err(e) =
+                                hold_or_kick(login, &header, &config, status, messages).await
+                            {
+                                error!("{}", e);
+                            }
+                        }
+                        Ok(ConnectionResult::ServerListPing) => {
+                            info!("Finished a server list ping")
+                        }
+                        Ok(ConnectionResult::Limited) => (),
+                        Err(e) => limiter.failed(&e),
+                    }
+                });
+            }
+        }
+    }
+
+    // What the real server speaks, from the config or else from the last status it sent us
+    fn backend_protocol(&self) -> Option<i32> {
+        let cached = || {
+            self.status_cache
+                .as_ref()
+                .and_then(|cache| cache.protocol())
+        };
+        self.config.backend.protocol().or_else(cached)
+    }
+
+    fn handle(&mut self, message: Message) {
+        match message {
+            Message::Event(event) => self.fire(event),
+            Message::Started(addr) => {
+                if let Some(started_at) = self.started_at.take() {
+                    self.expected_start = started_at.elapsed();
+                    info!("Real server took {:?} to start", self.expected_start);
+                }
+                self.cache_status(addr.clone());
+                self.publish(|status| {
+                    status.backend_addr = Some(addr);
+                    status.ready_at = None;
+                });
+                self.fire(Event::BackendReady);
+            }
+            Message::Timeout(generation) if generation == self.generation => {
+                self.fire(Event::IdleTimeout)
+            }
+            Message::Timeout(_) => trace!("Ignoring a stale idle timeout"),
+            Message::ConnectionOpened => {
+                self.active_connections += 1;
+                self.fire(Event::PlayerJoined);
+            }
+            Message::ConnectionClosed => {
+                self.active_connections -= 1;
+                if self.active_connections == 0 && !self.watching {
+                    self.fire(Event::Empty);
+                }
+            }
+            Message::Accepted(socket, peer, permit) => self.dispatch(socket, peer, Some(permit)),
+            Message::WatcherFailed => {
+                self.watching = false;
+                match self.state() {
+                    State::Running if self.active_connections == 0 => self.fire(Event::Empty),
+                    // Already empty, but nothing is timing it
+                    State::Draining => self.enter(State::Draining),
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    fn fire(&mut self, event: Event) {
+        let from = self.state();
+        let to = match from.next(event) {
+            Some(to) => to,
+            None if from == State::Stopping && event == Event::LoginAttempt => {
+                info!("Someone logged in, starting the real server again once it's stopped");
+                self.wake_after_stop = true;
+                return;
+            }
+            None => {
+                trace!("Ignoring {:?} while {:?}", event, from);
+                return;
+            }
+        };
+        info!("{:?} -> {:?} ({:?})", from, to, event);
+        self.generation += 1;
+        self.publish(|status| status.state = to);
+        self.enter(to);
+    }
+
+    // Kick off whatever work the new state needs, which reports back through the channel
+    fn enter(&mut self, state: State) {
+        let messages = self.messages_tx.clone();
+        match state {
+            State::Sleeping => {
+                self.watching = false;
+                self.started_at = None;
+                self.publish(|status| {
+                    status.backend_addr = None;
+                    status.ready_at = None;
+                });
+                if std::mem::take(&mut self.wake_after_stop) {
+                    self.fire(Event::LoginAttempt);
+                }
+            }
+            State::Starting => {
+                let now = Instant::now();
+                self.started_at = Some(now);
+                let ready_at = now + self.expected_start;
+                self.publish(|status| status.ready_at = Some(ready_at));
+                let provisioner = self.provisioner.clone();
+                let timeout = self.config.backend.start_timeout();
+                tokio::spawn(async move {
+                    let message = match start_backend(&*provisioner, timeout).await {
+                        Ok(addr) => Message::Started(addr),
+                        Err(e) => {
+                            error!("Real server didn't come up: {}", e);
+                            Message::Event(Event::BackendFailed)
+                        }
+                    };
+                    let _ = messages.send(message);
+                });
+            }
+            State::Running if self.watching => (),
+            State::Running => match self.config.rcon.password.clone() {
+                Some(password) => {
+                    self.watching = true;
+                    self.watch_idle(password);
+                }
+                // The player who woke the server was kicked, so nobody may be connected yet.
+                // Draining gives them the idle timeout to come back.
+                None if self.active_connections == 0 => self.fire(Event::Empty),
+                None => (),
+            },
+            State::Draining if self.watching => (),
+            State::Draining => {
+                let generation = self.generation;
+                let timeout = self.config.idle.shutdown_after();
+                tokio::spawn(async move {
+                    sleep(timeout).await;
+                    let _ = messages.send(Message::Timeout(generation));
+                });
+            }
+            State::Stopping => {
+                let provisioner = self.provisioner.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = provisioner.stop().await {
+                        error!("Couldn't stop the real server cleanly: {}", e);
+                    }
+                    let _ = messages.send(Message::Event(Event::Stopped));
+                });
+            }
+        }
+    }
+
+    // Keep a copy of the real server's status up to date for as long as it's up
+    fn cache_status(&self, addr: String) {
+        let cache = match &self.status_cache {
+            Some(cache) => cache.clone(),
+            None => return,
+        };
+        let status = self.subscribe();
+        let interval = self.config.idle.poll_interval();
+        tokio::spawn(async move {
+            loop {
+                match ping(&addr).await {
+                    Ok(result) => {
+                        if let Err(e) = cache.store(&result.status) {
+                            warn!("Can't use the real server's status: {}", e);
+                        }
+                    }
+                    Err(e) => debug!("Couldn't fetch the real server's status: {}", e),
+                }
+                sleep(interval).await;
+                let current = status.borrow();
+                let up = matches!(current.state, State::Running | State::Draining);
+                if !up || current.backend_addr.as_ref() != Some(&addr) {
+                    break;
+                }
+            }
+        });
+    }
+
+    fn watch_idle(&self, password: String) {
+        let config = &self.config;
+        let watcher = IdleWatcher {
+            rcon_addr: config.rcon.address.clone(),
+            password,
+            poll_interval: config.idle.poll_interval(),
+            grace_period: config.idle.shutdown_after(),
+        };
+        let (activity_tx, mut activity_rx) = mpsc::unbounded_channel();
+        let messages = self.messages_tx.clone();
+        let failed = self.messages_tx.clone();
+        tokio::spawn(async move {
+            if let Err(e) = watcher.run(activity_tx).await {
+                error!("Stopped watching the real server over rcon: {}", e);
+                let _ = failed.send(Message::WatcherFailed);
+            }
+        });
+        tokio::spawn(async move {
+            while let Some(activity) = activity_rx.recv().await {
+                let event = match activity {
+                    Activity::Occupied => Event::PlayerJoined,
+                    Activity::Empty => Event::Empty,
+                    Activity::Idle => Event::IdleTimeout,
+                };
+                let _ = messages.send(Message::Event(event));
+            }
+        });
+    }
+}
+
+// Look at the handshake first so server list pings don't count as players
+async fn proxy_transparently(
+    socket: TcpStream,
+    peer: SocketAddr,
+    backend_addr: String,
+    header: &[u8],
+    config: &Config,
+    messages: mpsc::UnboundedSender<Message>,
+) -> Result<(), Error> {
+    // Old clients' pings aren't framed like anything else, so those just get passed along
+    if is_legacy_ping(&socket).await? {
+        return proxy(socket, backend_addr, header).await;
+    }
+    let mut intercepted = intercept(socket, Framing::default()).await?;
+    intercepted.peer = peer;
+    if !intercepted.is_login() {
+        return replay(intercepted, backend_addr, header, &config.proxy).await;
+    }
+    let _ = messages.send(Message::ConnectionOpened);
+    let result = replay(intercepted, backend_addr, header, &config.proxy).await;
+    let _ = messages.send(Message::ConnectionClosed);
+    result
+}
+
+// Someone logged in while the real server was down. Either send them through once it's up or,
+// if we aren't holding logins (or it takes too long), turn them away.
+async fn hold_or_kick(
+    login: Intercepted,
+    header: &[u8],
+    config: &Config,
+    mut status: watch::Receiver<Status>,
+    messages: mpsc::UnboundedSender<Message>,
+) -> Result<(), Error> {
+    let server = &config.server;
+    if !server.hold_logins || status.borrow().state == State::Stopping {
+        return kick(login, &Component::from_legacy(&server.kick_message)).await;
+    }
+    let backend_addr = match timeout(server.hold_timeout(), wait_for_backend(&mut status)).await {
+        Ok(Some(addr)) => addr,
+        _ => {
+            let eta = describe_eta(status.borrow().ready_at);
+            let reason = server.hold_expired_message.replace("{eta}", &eta);
+            return kick(login, &Component::from_legacy(&reason)).await;
+        }
+    };
+    debug!("Sending a held login through to {}", backend_addr);
+    let _ = messages.send(Message::ConnectionOpened);
+    let result = replay(login, backend_addr, header, &config.proxy).await;
+    let _ = messages.send(Message::ConnectionClosed);
+    result
+}
+
+// Wait until players can be proxied, or None if the real server failed to start
+async fn wait_for_backend(status: &mut watch::Receiver<Status>) -> Option<String> {
+    let mut seen_starting = false;
+    loop {
+        {
+            let current = status.borrow();
+            match current.state {
+                State::Running | State::Draining => return current.backend_addr.clone(),
+                State::Starting => seen_starting = true,
+                State::Sleeping if seen_starting => return None,
+                State::Sleeping => (),
+                State::Stopping => return None,
+            }
+        }
+        status.changed().await.ok()?;
+    }
+}
+
+/// What the fake server shows in the server list: the motd, then what the real server is up to
+pub fn describe_status(config: &ServerConfig, status: &Status) -> String {
+    let line = match status.state {
+        State::Sleeping => &config.sleeping_status,
+        State::Starting => &config.starting_status,
+        State::Running | State::Draining => &config.online_status,
+        State::Stopping => &config.stopping_status,
+    };
+    if line.is_empty() {
+        return config.motd.clone();
+    }
+    let line = line.replace("{eta}", &describe_eta(status.ready_at));
+    format!("{}\n{}", config.motd, line)
+}
+
+/// Something like "about 40 seconds", for telling people when the real server will be up
+pub fn describe_eta(ready_at: Option<Instant>) -> String {
+    let remaining = match ready_at {
+        Some(ready_at) => ready_at.saturating_duration_since(Instant::now()).as_secs(),
+        None => 0,
+    };
+    match remaining {
+        0..=4 => "a moment".to_owned(),
+        5..=89 => format!("about {} seconds", remaining),
+        _ => format!("about {} minutes", (remaining + 30) / 60),
+    }
+}
+
+// Start the real server and wait for it to answer pings, returning where it lives
+async fn start_backend(provisioner: &dyn Provisioner, timeout: Duration) -> Result<String, Error> {
+    let deadline = Instant::now() + timeout;
+    provisioner.start().await?;
+    let addr = provisioner.address().await?;
+    loop {
+        // A ping that hangs can't take it past the deadline
+        let left = deadline.saturating_duration_since(Instant::now());
+        match ping_within(&addr, left.min(PING_TIMEOUT)).await {
+            Ok(_) => return Ok(addr),
+            Err(e) if Instant::now() >= deadline => {
+                return Err(Error::Timeout(format!(
+                    "real server wasn't up after {:?} ({})",
+                    timeout, e
+                )))
+            }
+            Err(e) => trace!("Real server isn't up yet: {}", e),
+        }
+        let left = deadline.saturating_duration_since(Instant::now());
+        sleep(BACKEND_POLL_INTERVAL.min(left)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ServerConfig, WakePolicyConfig};
+    use crate::server::packet::{Handshake, LoginDisconnect};
+    use crate::server::read::packet::read_packet;
+    use crate::server::write::packet::write;
+    use std::net::SocketAddr;
+    use tokio::io::AsyncWriteExt;
+
+    #[test]
+    fn test_full_cycle() {
+        let mut state = State::Sleeping;
+        for event in [
+            Event::LoginAttempt,
+            Event::BackendReady,
+            Event::Empty,
+            Event::PlayerJoined,
+            Event::Empty,
+            Event::IdleTimeout,
+            Event::Stopped,
+        ]
+        .iter()
+        {
+            state = state.next(*event).unwrap();
+        }
+        assert_eq!(State::Sleeping, state);
+    }
+
+    #[test]
+    fn test_ignored_events() {
+        assert_eq!(None, State::Starting.next(Event::LoginAttempt));
+        assert_eq!(None, State::Running.next(Event::PlayerJoined));
+        assert_eq!(None, State::Stopping.next(Event::PlayerJoined));
+        assert_eq!(
+            Some(State::Sleeping),
+            State::Starting.next(Event::BackendFailed)
+        );
+    }
+
+    // Does what it's told straight away, with a real server that never answers
+    struct StubProvisioner;
+
+    impl Provisioner for StubProvisioner {
+        fn start(&self) -> futures::future::BoxFuture<'_, Result<(), Error>> {
+            Box::pin(async { Ok(()) })
+        }
+        fn stop(&self) -> futures::future::BoxFuture<'_, Result<(), Error>> {
+            Box::pin(async { Ok(()) })
+        }
+        fn status(&self) -> futures::future::BoxFuture<'_, Result<BackendStatus, Error>> {
+            Box::pin(async { Ok(BackendStatus::Stopped) })
+        }
+        fn address(&self) -> futures::future::BoxFuture<'_, Result<String, Error>> {
+            Box::pin(async { Ok("127.0.0.1:1".to_owned()) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_login_while_stopping() -> Result<(), Error> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let mut supervisor =
+            Supervisor::with_provisioner(Config::default(), listener, Arc::new(StubProvisioner))?;
+        supervisor.publish(|status| status.state = State::Stopping);
+        supervisor.handle(Message::Event(Event::LoginAttempt));
+        assert_eq!(State::Stopping, supervisor.state());
+        supervisor.handle(Message::Event(Event::Stopped));
+        assert_eq!(State::Starting, supervisor.state());
+        // Only the once
+        supervisor.handle(Message::Event(Event::BackendFailed));
+        assert_eq!(State::Sleeping, supervisor.state());
+        Ok(())
+    }
+
+    // A "real" server that is just another fake server, so the lifecycle has something to ping.
+    // It kicks anyone who logs in with a different message than the facade's.
+    async fn fake_backend() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let config = ServerConfig::default();
+            loop {
+                let (socket, peer) = listener.accept().await.unwrap();
+                let limiter = Limiter::default();
+                if let Ok(ConnectionResult::Login(login)) =
+                    handle_connection(socket, peer, &config, &Listing::default(), &limiter).await
+                {
+                    kick(login, &Component::text("Welcome to the real server"))
+                        .await
+                        .unwrap();
+                }
+            }
+        });
+        addr
+    }
+
+    // Log in and return the disconnect message we get back
+    async fn log_in(addr: std::net::SocketAddr) -> Result<String, Error> {
+        let mut socket = TcpStream::connect(addr).await?;
+        let handshake = Handshake {
+            protocol_version: 754,
+            server_address: "localhost".to_owned(),
+            server_port: addr.port(),
+            next_state: 2,
+        };
+        write(&handshake, &mut socket).await?;
+        socket
+            .write_all(&[0x05, 0x00, 0x03, b'b', b'o', b'b'])
+            .await?; // login start
+        let disconnect: LoginDisconnect = read_packet(&mut socket).await?;
+        Ok(disconnect.reason)
+    }
+
+    async fn start_supervisor(
+        config: Config,
+    ) -> Result<(SocketAddr, watch::Receiver<Status>), Error> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let supervisor = Supervisor::new(config, listener)?;
+        let status = supervisor.subscribe();
+        tokio::spawn(supervisor.run());
+        Ok((addr, status))
+    }
+
+    #[tokio::test]
+    async fn test_login_wakes_backend() -> Result<(), Error> {
+        let mut config = Config::default();
+        config.backend.address = Some(fake_backend().await);
+        let (addr, mut status) = start_supervisor(config).await?;
+
+        let reason = log_in(addr).await?;
+        assert!(reason.contains("Starting the real server"));
+
+        // Running goes straight to Draining since nobody is connected, so the watch may skip it
+        status.changed().await.unwrap();
+        assert_eq!(State::Starting, status.borrow().state);
+        while status.borrow().state != State::Draining {
+            status.changed().await.unwrap();
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_held_login_reaches_backend() -> Result<(), Error> {
+        let mut config = Config::default();
+        config.backend.address = Some(fake_backend().await);
+        config.server.hold_logins = true;
+        let (addr, _) = start_supervisor(config).await?;
+
+        let reason = log_in(addr).await?;
+        assert!(reason.contains("Welcome to the real server"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_incompatible_login_stays_asleep() -> Result<(), Error> {
+        let mut config = Config::default();
+        config.backend.address = Some(fake_backend().await);
+        config.backend.version = Some("1.20.4".to_owned());
+        let (addr, status) = start_supervisor(config).await?;
+
+        let reason = log_in(addr).await?; // as a 1.16.5 client
+        assert!(reason.contains("This server runs 1.20.4"));
+        assert_eq!(State::Sleeping, status.borrow().state);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_online_mode_needs_encryption() -> Result<(), Error> {
+        use crate::server::packet::EncryptionRequest;
+        // Nothing's there, so a wrong wake would leave it Starting rather than skipping to Draining
+        let closed = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+        let mut config = Config::default();
+        config.backend.address = Some(closed.to_string());
+        config.server.online_mode = true;
+        let (addr, status) = start_supervisor(config).await?;
+
+        // Just a name isn't enough any more
+        let mut socket = TcpStream::connect(addr).await?;
+        let handshake = Handshake {
+            protocol_version: 754,
+            server_address: "localhost".to_owned(),
+            server_port: addr.port(),
+            next_state: 2,
+        };
+        write(&handshake, &mut socket).await?;
+        socket
+            .write_all(&[0x05, 0x00, 0x03, b'b', b'o', b'b'])
+            .await?; // login start
+        let request: EncryptionRequest = read_packet(&mut socket).await?;
+        assert_eq!(4, request.verify_token.len());
+        drop(socket);
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!(State::Sleeping, status.borrow().state);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_allowlist_gates_wake() -> Result<(), Error> {
+        let file =
+            std::env::temp_dir().join(format!("facade-allowlist-test-{}", std::process::id()));
+        std::fs::write(&file, "alice\n")?;
+        let closed = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+        let mut config = Config::default();
+        config.backend.address = Some(closed.to_string());
+        config.server.allowlist = Some(file.to_string_lossy().into_owned());
+        let result = start_supervisor(config).await;
+        std::fs::remove_file(&file)?;
+        let (addr, status) = result?;
+
+        // bob isn't on it
+        let reason = log_in(addr).await?;
+        assert!(reason.contains("not on the list"), "{}", reason);
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!(State::Sleeping, status.borrow().state);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_wake_policy_defers() -> Result<(), Error> {
+        let closed = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+        let mut config = Config::default();
+        config.backend.address = Some(closed.to_string());
+        config.wake.policy = Some(WakePolicyConfig::Confirm { within_secs: 60 });
+        let (addr, status) = start_supervisor(config).await?;
+
+        let reason = log_in(addr).await?;
+        assert!(reason.contains("Noted!"), "{}", reason);
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!(State::Sleeping, status.borrow().state);
+
+        let reason = log_in(addr).await?;
+        assert!(reason.contains("Starting the real server"), "{}", reason);
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!(State::Starting, status.borrow().state);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_limits() -> Result<(), Error> {
+        use crate::server::client::ping;
+        use tokio::io::AsyncReadExt;
+        let closed = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+        let mut config = Config::default();
+        config.backend.address = Some(closed.to_string());
+        config.limits.handshake_timeout_secs = 1;
+        config.limits.pings_per_minute = 1;
+        let (addr, _) = start_supervisor(config).await?;
+
+        assert!(ping(&addr.to_string()).await.is_ok());
+        assert!(ping(&addr.to_string()).await.is_err());
+
+        // Saying nothing gets the connection closed
+        let mut socket = TcpStream::connect(addr).await?;
+        let closed = timeout(Duration::from_secs(3), socket.read(&mut [0; 1])).await;
+        assert!(matches!(closed, Ok(Ok(0))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_proxy_protocol_limits_real_address() -> Result<(), Error> {
+        use crate::proxy_protocol::{header, Version};
+        use crate::server::packet::{StatusRequest, StatusResponse};
+        let closed = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+        let mut config = Config::default();
+        config.backend.address = Some(closed.to_string());
+        config.limits.pings_per_minute = 1;
+        config.proxy_protocol.accept = true;
+        config.proxy_protocol.trusted = vec!["127.0.0.1".parse().unwrap()];
+        let (addr, _) = start_supervisor(config).await?;
+
+        let ping_from = |source: &'static str| async move {
+            let mut socket = TcpStream::connect(addr).await?;
+            let source = source.parse().unwrap();
+            socket.write_all(&header(Version::V1, source, addr)).await?;
+            let handshake = Handshake {
+                protocol_version: 754,
+                server_address: "localhost".to_owned(),
+                server_port: addr.port(),
+                next_state: 1,
+            };
+            write(&handshake, &mut socket).await?;
+            write(&StatusRequest {}, &mut socket).await?;
+            read_packet::<StatusResponse, _>(&mut socket).await
+        };
+        // All from the same balancer, but only one player is pinging too often
+        assert!(ping_from("203.0.113.7:50000").await.is_ok());
+        assert!(ping_from("203.0.113.7:50001").await.is_err());
+        assert!(ping_from("203.0.113.8:50000").await.is_ok());
+
+        // Without a header it's anyone's guess where it came from
+        assert!(ping(&addr.to_string()).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_proxy_protocol_untrusted() -> Result<(), Error> {
+        use crate::proxy_protocol::{header, Version};
+        use tokio::io::AsyncReadExt;
+        let closed = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+        let mut config = Config::default();
+        config.backend.address = Some(closed.to_string());
+        config.proxy_protocol.accept = true;
+        config.proxy_protocol.trusted = vec!["10.0.0.2".parse().unwrap()];
+        let (addr, _) = start_supervisor(config).await?;
+
+        // Only the balancer gets to say where a connection is from
+        let mut socket = TcpStream::connect(addr).await?;
+        let source = "203.0.113.7:50000".parse().unwrap();
+        let _ = socket.write_all(&header(Version::V1, source, addr)).await;
+        let closed = timeout(Duration::from_secs(3), socket.read(&mut [0; 1])).await;
+        assert!(matches!(closed, Ok(Ok(0)) | Ok(Err(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_describe_status() {
+        let mut config = ServerConfig {
+            motd: "My Server".to_owned(),
+            ..Default::default()
+        };
+        let mut status = Status {
+            state: State::Sleeping,
+            backend_addr: None,
+            ready_at: None,
+        };
+        assert_eq!(
+            "My Server\n§7Sleeping - join to wake",
+            describe_status(&config, &status)
+        );
+        status.state = State::Starting;
+        status.ready_at = Some(Instant::now() + Duration::from_secs(170));
+        assert_eq!(
+            "My Server\n§eStarting, ready in about 3 minutes",
+            describe_status(&config, &status)
+        );
+        config.stopping_status = "".to_owned();
+        status.state = State::Stopping;
+        assert_eq!("My Server", describe_status(&config, &status));
+    }
+
+    #[test]
+    fn test_describe_eta() {
+        let now = Instant::now();
+        assert_eq!("a moment", describe_eta(None));
+        assert_eq!("a moment", describe_eta(Some(now)));
+        assert!(describe_eta(Some(now + Duration::from_secs(46))).ends_with("seconds"));
+        assert_eq!(
+            "about 3 minutes",
+            describe_eta(Some(now + Duration::from_secs(170)))
+        );
+    }
+}
@@ -0,0 +1,60 @@
+use pin_project_lite::pin_project;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, ReadBuf};
+
+pin_project! {
+    /// Keeps a copy of everything read through it, so bytes that have already been parsed
+    /// can be replayed to somewhere else
+    pub struct Recorder<S> {
+        #[pin]
+        inner: S,
+        recorded: Vec<u8>,
+    }
+}
+
+impl<S> Recorder<S> {
+    pub fn new(inner: S) -> Self {
+        Recorder {
+            inner,
+            recorded: vec![],
+        }
+    }
+
+    pub fn into_parts(self) -> (S, Vec<u8>) {
+        (self.inner, self.recorded)
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for Recorder<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let before = buf.filled().len();
+        let result = this.inner.poll_read(cx, buf);
+        this.recorded.extend_from_slice(&buf.filled()[before..]);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn test_records_reads() -> io::Result<()> {
+        let source: &[u8] = &[1, 2, 3, 4, 5];
+        let mut recorder = Recorder::new(source);
+        let mut buf = [0; 3];
+        recorder.read_exact(&mut buf).await?;
+        let (rest, recorded) = recorder.into_parts();
+        assert_eq!(vec![1, 2, 3], recorded);
+        assert_eq!(&[4, 5], rest);
+        Ok(())
+    }
+}
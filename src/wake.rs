@@ -0,0 +1,424 @@
+/*
+    Who gets to wake the real server. Every login while it's asleep is put to a WakePolicy, which
+    can wake it, turn the player away, or defer: kick them as usual without waking anything, for
+    policies that need more than one login to make up their mind.
+    The built in policies can be combined with All and Any, and picked from the [wake] config.
+*/
+
+use crate::allowlist::Allowlist;
+use crate::config::{Config, WakePolicyConfig};
+use crate::error::Error;
+use crate::server::packet::Handshake;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Everything a policy gets to go on
+#[derive(Debug, Clone, Copy)]
+pub struct WakeRequest<'a> {
+    // None of the built in policies care which address or version they were asked for
+    #[allow(dead_code)]
+    pub handshake: &'a Handshake,
+    /// Checked with the session server in online mode, and taken on trust otherwise
+    pub player: &'a str,
+    /// Only sent by newer clients outside online mode, and no more checked than the name
+    pub uuid: Option<u128>,
+    pub peer: SocketAddr,
+    pub at: Instant,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decision {
+    Wake,
+    /// Don't wake the real server, and tell the player why
+    Reject(String),
+    /// Don't wake the real server yet
+    Defer,
+}
+
+/// Decides whether a login wakes the real server. Policies can keep track of what they've been
+/// asked, so a request should only be put to them once.
+pub trait WakePolicy: Send + Sync {
+    fn decide(&self, request: &WakeRequest<'_>) -> Decision;
+}
+
+/// Build the policy the config asks for
+pub fn from_config(config: &Config) -> Result<Arc<dyn WakePolicy>, Error> {
+    let allowlist = Allowlist::from_config(&config.server)?.map(Arc::new);
+    let policy = match &config.wake.policy {
+        Some(policy) => build(policy, config, &allowlist)?,
+        // Having an allowlist is enough to use it
+        None if allowlist.is_some() => build(&WakePolicyConfig::Allowlisted, config, &allowlist)?,
+        None => Box::new(AnyLogin),
+    };
+    Ok(Arc::from(policy))
+}
+
+fn build(
+    policy: &WakePolicyConfig,
+    config: &Config,
+    allowlist: &Option<Arc<Allowlist>>,
+) -> Result<Box<dyn WakePolicy>, Error> {
+    let secs = Duration::from_secs;
+    let build_all = |policies: &[WakePolicyConfig]| {
+        policies
+            .iter()
+            .map(|policy| build(policy, config, allowlist))
+            .collect::<Result<Vec<_>, _>>()
+    };
+    Ok(match policy {
+        WakePolicyConfig::AnyLogin => Box::new(AnyLogin),
+        WakePolicyConfig::Allowlisted => {
+            let allowlist = allowlist.clone().ok_or_else(|| {
+                Error::config("The allowlisted wake policy needs server.allowlist")
+            })?;
+            Box::new(Allowlisted::new(
+                allowlist,
+                &config.server.not_allowed_message,
+            ))
+        }
+        WakePolicyConfig::DistinctPlayers { count, within_secs } => {
+            Box::new(DistinctPlayers::new(*count, secs(*within_secs)))
+        }
+        WakePolicyConfig::Confirm { within_secs } => Box::new(Confirm::new(secs(*within_secs))),
+        WakePolicyConfig::Cooldown { secs: cooldown } => Box::new(Cooldown::new(secs(*cooldown))),
+        WakePolicyConfig::All { of } => Box::new(All(build_all(of)?)),
+        WakePolicyConfig::Any { of } => Box::new(Any(build_all(of)?)),
+    })
+}
+
+/// The first login wakes the real server
+pub struct AnyLogin;
+
+impl WakePolicy for AnyLogin {
+    fn decide(&self, _: &WakeRequest<'_>) -> Decision {
+        Decision::Wake
+    }
+}
+
+/// Only players on the allowlist wake the real server
+pub struct Allowlisted {
+    allowlist: Arc<Allowlist>,
+    message: String,
+}
+
+impl Allowlisted {
+    pub fn new(allowlist: Arc<Allowlist>, message: &str) -> Self {
+        Allowlisted {
+            allowlist,
+            message: message.to_owned(),
+        }
+    }
+}
+
+impl WakePolicy for Allowlisted {
+    fn decide(&self, request: &WakeRequest<'_>) -> Decision {
+        match self.allowlist.allows(request.player, request.uuid) {
+            true => Decision::Wake,
+            false => Decision::Reject(self.message.clone()),
+        }
+    }
+}
+
+/// Wait for `count` different players to try logging in within `window` of each other
+pub struct DistinctPlayers {
+    count: usize,
+    window: Duration,
+    // Lowercase names, and when we last saw them
+    seen: Mutex<HashMap<String, Instant>>,
+}
+
+impl DistinctPlayers {
+    pub fn new(count: usize, window: Duration) -> Self {
+        DistinctPlayers {
+            count,
+            window,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl WakePolicy for DistinctPlayers {
+    fn decide(&self, request: &WakeRequest<'_>) -> Decision {
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|_, at| request.at.saturating_duration_since(*at) <= self.window);
+        seen.insert(request.player.to_lowercase(), request.at);
+        if seen.len() < self.count {
+            return Decision::Defer;
+        }
+        // Start counting again for the next time it's asleep
+        seen.clear();
+        Decision::Wake
+    }
+}
+
+/// Make players log in twice within `window` to wake the real server, so a stray click on the
+/// server list (or a bot that gives up after one try) doesn't start it
+pub struct Confirm {
+    window: Duration,
+    first_tries: Mutex<HashMap<String, Instant>>,
+}
+
+impl Confirm {
+    pub fn new(window: Duration) -> Self {
+        Confirm {
+            window,
+            first_tries: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl WakePolicy for Confirm {
+    fn decide(&self, request: &WakeRequest<'_>) -> Decision {
+        let mut first_tries = self.first_tries.lock().unwrap();
+        first_tries.retain(|_, at| request.at.saturating_duration_since(*at) <= self.window);
+        let player = request.player.to_lowercase();
+        match first_tries.remove(&player) {
+            Some(_) => Decision::Wake,
+            None => {
+                first_tries.insert(player, request.at);
+                Decision::Defer
+            }
+        }
+    }
+}
+
+/// Turn away addresses that tried to wake the real server less than `cooldown` ago
+pub struct Cooldown {
+    cooldown: Duration,
+    last_tries: Mutex<HashMap<IpAddr, Instant>>,
+}
+
+impl Cooldown {
+    pub fn new(cooldown: Duration) -> Self {
+        Cooldown {
+            cooldown,
+            last_tries: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl WakePolicy for Cooldown {
+    fn decide(&self, request: &WakeRequest<'_>) -> Decision {
+        let ip = request.peer.ip();
+        let mut last_tries = self.last_tries.lock().unwrap();
+        last_tries.retain(|_, at| request.at.saturating_duration_since(*at) < self.cooldown);
+        if let Some(at) = last_tries.get(&ip) {
+            let wait = self.cooldown - request.at.saturating_duration_since(*at);
+            return Decision::Reject(format!(
+                "Slow down! Try again in {} seconds",
+                wait.as_secs().max(1)
+            ));
+        }
+        last_tries.insert(ip, request.at);
+        Decision::Wake
+    }
+}
+
+/// Wake only if every policy would. They're asked in order, stopping at the first rejection.
+pub struct All(pub Vec<Box<dyn WakePolicy>>);
+
+impl WakePolicy for All {
+    fn decide(&self, request: &WakeRequest<'_>) -> Decision {
+        let mut decision = Decision::Wake;
+        for policy in &self.0 {
+            match policy.decide(request) {
+                Decision::Wake => (),
+                Decision::Defer => decision = Decision::Defer,
+                reject => return reject,
+            }
+        }
+        decision
+    }
+}
+
+/// Wake if any policy would. They're asked in order, stopping at the first one that wakes. If
+/// none do, it's deferred if any of them deferred, otherwise the first rejection is passed on.
+pub struct Any(pub Vec<Box<dyn WakePolicy>>);
+
+impl WakePolicy for Any {
+    fn decide(&self, request: &WakeRequest<'_>) -> Decision {
+        let mut rejection = None;
+        let mut deferred = false;
+        for policy in &self.0 {
+            match policy.decide(request) {
+                Decision::Wake => return Decision::Wake,
+                Decision::Defer => deferred = true,
+                reject => {
+                    rejection.get_or_insert(reject);
+                }
+            }
+        }
+        match (deferred, rejection) {
+            (true, _) => Decision::Defer,
+            (false, Some(reject)) => reject,
+            // No policies at all
+            (false, None) => Decision::Wake,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handshake() -> Handshake {
+        Handshake {
+            protocol_version: 754,
+            server_address: "localhost".to_owned(),
+            server_port: 25565,
+            next_state: 2,
+        }
+    }
+
+    fn request<'a>(
+        handshake: &'a Handshake,
+        player: &'a str,
+        ip: &str,
+        at: Instant,
+    ) -> WakeRequest<'a> {
+        WakeRequest {
+            handshake,
+            player,
+            uuid: None,
+            peer: SocketAddr::new(ip.parse().unwrap(), 50000),
+            at,
+        }
+    }
+
+    const SEC: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn test_distinct_players() {
+        let handshake = handshake();
+        let start = Instant::now();
+        let ask = |policy: &DistinctPlayers, player, secs| {
+            policy.decide(&request(&handshake, player, "10.0.0.1", start + secs * SEC))
+        };
+        let policy = DistinctPlayers::new(3, 60 * SEC);
+        assert_eq!(Decision::Defer, ask(&policy, "alice", 0));
+        // The same player again doesn't count
+        assert_eq!(Decision::Defer, ask(&policy, "Alice", 10));
+        assert_eq!(Decision::Defer, ask(&policy, "bob", 20));
+        assert_eq!(Decision::Wake, ask(&policy, "carol", 30));
+        // Starts over afterwards, and forgets players outside the window
+        assert_eq!(Decision::Defer, ask(&policy, "alice", 100));
+        assert_eq!(Decision::Defer, ask(&policy, "bob", 110));
+        assert_eq!(Decision::Defer, ask(&policy, "carol", 200));
+    }
+
+    #[test]
+    fn test_confirm() {
+        let handshake = handshake();
+        let start = Instant::now();
+        let ask = |policy: &Confirm, player, secs| {
+            policy.decide(&request(&handshake, player, "10.0.0.1", start + secs * SEC))
+        };
+        let policy = Confirm::new(30 * SEC);
+        assert_eq!(Decision::Defer, ask(&policy, "alice", 0));
+        assert_eq!(Decision::Defer, ask(&policy, "bob", 5));
+        assert_eq!(Decision::Wake, ask(&policy, "alice", 10));
+        // Too late
+        assert_eq!(Decision::Defer, ask(&policy, "bob", 60));
+        assert_eq!(Decision::Wake, ask(&policy, "bob", 61));
+    }
+
+    #[test]
+    fn test_cooldown() {
+        let handshake = handshake();
+        let start = Instant::now();
+        let ask = |policy: &Cooldown, ip, secs| {
+            policy.decide(&request(&handshake, "alice", ip, start + secs * SEC))
+        };
+        let policy = Cooldown::new(60 * SEC);
+        assert_eq!(Decision::Wake, ask(&policy, "10.0.0.1", 0));
+        assert_eq!(
+            Decision::Reject("Slow down! Try again in 50 seconds".to_owned()),
+            ask(&policy, "10.0.0.1", 10)
+        );
+        assert_eq!(Decision::Wake, ask(&policy, "10.0.0.2", 10));
+        assert_eq!(Decision::Wake, ask(&policy, "10.0.0.1", 60));
+    }
+
+    #[test]
+    fn test_combinators() -> Result<(), Error> {
+        let handshake = handshake();
+        let now = Instant::now();
+        let allowlist = Arc::new(Allowlist::parse("alice\n")?);
+        let allowlisted = || Box::new(Allowlisted::new(allowlist.clone(), "Members only"));
+        let reject = || Decision::Reject("Members only".to_owned());
+
+        // Members wake it straight away, anyone else needs a friend
+        let policy = Any(vec![
+            allowlisted(),
+            Box::new(DistinctPlayers::new(2, 60 * SEC)),
+        ]);
+        assert_eq!(
+            Decision::Wake,
+            policy.decide(&request(&handshake, "alice", "10.0.0.1", now))
+        );
+        assert_eq!(
+            Decision::Defer,
+            policy.decide(&request(&handshake, "bob", "10.0.0.2", now))
+        );
+        assert_eq!(
+            Decision::Wake,
+            policy.decide(&request(&handshake, "carol", "10.0.0.3", now))
+        );
+        assert_eq!(
+            reject(),
+            Any(vec![allowlisted()]).decide(&request(&handshake, "bob", "10.0.0.2", now))
+        );
+
+        // Members have to confirm, and anyone else is turned away without being remembered
+        let policy = All(vec![allowlisted(), Box::new(Confirm::new(60 * SEC))]);
+        assert_eq!(
+            reject(),
+            policy.decide(&request(&handshake, "bob", "10.0.0.2", now))
+        );
+        assert_eq!(
+            Decision::Defer,
+            policy.decide(&request(&handshake, "alice", "10.0.0.1", now))
+        );
+        assert_eq!(
+            Decision::Wake,
+            policy.decide(&request(&handshake, "alice", "10.0.0.1", now))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_config() -> Result<(), Error> {
+        let handshake = handshake();
+        let now = Instant::now();
+        let config = Config::parse(
+            r#"
+            [wake.policy]
+            kind = "all"
+            of = [
+                { kind = "cooldown", secs = 60 },
+                { kind = "distinct_players", count = 2, within_secs = 300 },
+            ]
+            "#,
+        )?;
+        // One address can't pass itself off as a crowd
+        let policy = from_config(&config)?;
+        assert_eq!(
+            Decision::Defer,
+            policy.decide(&request(&handshake, "alice", "10.0.0.1", now))
+        );
+        assert!(matches!(
+            policy.decide(&request(&handshake, "bob", "10.0.0.1", now)),
+            Decision::Reject(_)
+        ));
+        assert_eq!(
+            Decision::Wake,
+            policy.decide(&request(&handshake, "bob", "10.0.0.2", now))
+        );
+
+        let config = Config::parse("[wake]\npolicy = { kind = \"allowlisted\" }")?;
+        assert!(from_config(&config).is_err());
+        Ok(())
+    }
+}